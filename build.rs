@@ -0,0 +1,33 @@
+use std::{env, path::Path, process};
+
+// The seccomp notification API used by the supervisor is only compiled in by the libseccomp
+// crate when pkg-config finds libseccomp 2.5.0 or later, fail early with the reason instead
+// of unresolved imports
+const LIBSECCOMP_MIN_VERSION: &str = "2.5.0";
+// Also read by the libseccomp crate, the directory of libseccomp.so and of pkgconfig/
+const LIBSECCOMP_LIB_PATH: &str = "LIBSECCOMP_LIB_PATH";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={}", LIBSECCOMP_LIB_PATH);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    if let Ok(path) = env::var(LIBSECCOMP_LIB_PATH) {
+        env::set_var("PKG_CONFIG_PATH", Path::new(&path).join("pkgconfig"));
+    }
+    if env::var("TARGET").ok() != env::var("HOST").ok() {
+        env::set_var("PKG_CONFIG_ALLOW_CROSS", "1");
+    }
+
+    let probe = pkg_config::Config::new()
+        .atleast_version(LIBSECCOMP_MIN_VERSION)
+        .cargo_metadata(false)
+        .probe("libseccomp");
+    if let Err(e) = probe {
+        eprintln!(
+            "crabcan needs the development files of libseccomp {} or later, found through \
+             pkg-config (e.g. libseccomp-dev), or in the directory set by {}: {}",
+            LIBSECCOMP_MIN_VERSION, LIBSECCOMP_LIB_PATH, e
+        );
+        process::exit(1);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::errors::Errcode;
+use crate::logs::Stream;
+use crate::shim::{request, unexpected_response, Request, Response};
+use crate::state::ContainerState;
+use crate::tty::{write_all, RawTerminal};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::unistd::read;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+// A client not reading the outputs is disconnected, it can't block the container
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+// The outputs are sent in frames: the stream (1 for stdout, 2 for stderr) and the size
+// of the data as a big-endian u32, then the data, like the streams of docker attach
+const FRAME_HEADER_SIZE: usize = 5;
+const BUFFER_SIZE: usize = 4096;
+
+// The clients attached to a container, sharing its input and outputs
+#[derive(Debug, Clone)]
+pub struct AttachServer {
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+    input: Option<RawFd>,
+}
+
+impl AttachServer {
+    // What the clients send is written to input, the master of the terminal of the container
+    // or the pipe of its input. The server has its own copy, the input stays open for the
+    // clients until crabcan exits.
+    pub fn new(input: Option<RawFd>) -> Result<AttachServer, Errcode> {
+        let input = match input.map(|fd| fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0))) {
+            Some(Ok(fd)) => Some(fd),
+            Some(Err(e)) => {
+                log::error!("Cannot duplicate the input of the container: {:?}", e);
+                return Err(Errcode::AttachError(0));
+            }
+            None => None,
+        };
+        Ok(AttachServer {
+            clients: Arc::new(Mutex::new(Vec::new())),
+            input,
+        })
+    }
+
+    // Called for a client of the control socket asking to attach
+    pub fn add(&self, client: UnixStream) {
+        log::debug!("Client attached");
+        if let Err(e) = client.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)) {
+            log::error!("Cannot set the timeout of a client: {}", e);
+            return;
+        }
+        let reader = match client.try_clone() {
+            Ok(reader) => reader,
+            Err(e) => {
+                log::error!("Cannot read from a client: {}", e);
+                return;
+            }
+        };
+        self.lock().push(client);
+        let input = self.input;
+        thread::spawn(move || forward_input(reader, input));
+    }
+
+    // Sends an output of the container to every client, a client failing to receive it
+    // is disconnected
+    pub fn broadcast(&self, stream: Stream, data: &[u8]) {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+        frame.push(stream_id(stream));
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        self.lock().retain(|client| {
+            let mut writer: &UnixStream = client;
+            if writer.write_all(&frame).is_ok() {
+                return true;
+            }
+            log::debug!("Client detached");
+            let _ = client.shutdown(Shutdown::Both);
+            false
+        });
+    }
+
+    // The clients see the end of the outputs once the container exited
+    pub fn close(&self) {
+        for client in self.lock().drain(..) {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<UnixStream>> {
+        // A client is added or removed in a single step, the list is valid even if a thread panicked
+        match self.clients.lock() {
+            Ok(clients) => clients,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+// Without input, what the client sends is dropped
+fn forward_input(mut client: UnixStream, input: Option<RawFd>) {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    loop {
+        let size = match client.read(&mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(size) => size,
+        };
+        if let Some(fd) = input {
+            if !write_all(fd, &buffer[..size]) {
+                return;
+            }
+        }
+    }
+}
+
+fn stream_id(stream: Stream) -> u8 {
+    match stream {
+        Stream::Stdout => 1,
+        Stream::Stderr => 2,
+    }
+}
+
+// The key sequence typed to detach from a container without stopping it
+#[derive(Debug, Clone)]
+pub struct DetachKeys(pub Vec<u8>);
+
+// Parses keys separated by commas, each one a character or ctrl-<key>, like docker.
+// Without keys, crabcan attach only detaches once the container exits.
+impl FromStr for DetachKeys {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<DetachKeys, Errcode> {
+        if s.is_empty() {
+            return Ok(DetachKeys(Vec::new()));
+        }
+        s.split(',')
+            .map(|key| match (key.strip_prefix("ctrl-"), key.as_bytes()) {
+                (Some(ctrl), _) if ctrl.len() == 1 => {
+                    let c = ctrl.as_bytes()[0].to_ascii_uppercase();
+                    match c {
+                        b'@'..=b'_' => Ok(c & 0x1f),
+                        _ => Err(Errcode::ArgumentInvalid("detach-keys")),
+                    }
+                }
+                (None, [c]) => Ok(*c),
+                _ => Err(Errcode::ArgumentInvalid("detach-keys")),
+            })
+            .collect::<Result<Vec<u8>, Errcode>>()
+            .map(DetachKeys)
+    }
+}
+
+// Connects the terminal of crabcan, or its input and outputs, to a container,
+// until the container exits or the detach keys are typed
+pub fn attach(id: &str, keys: DetachKeys) -> Result<(), Errcode> {
+    let state = ContainerState::load(id)?;
+    if !state.is_active() {
+        log::error!("Container {} isn't running", id);
+        return Err(Errcode::ContainerError(8));
+    }
+    // A container whose terminal was sent to a console socket can't be attached
+    let socket = match request(id, &Request::Attach)? {
+        (Response::Attached, socket) => socket,
+        (response, _) => return Err(unexpected_response(response)),
+    };
+    let writer = match socket.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            log::error!("Cannot write to container {}: {}", id, e);
+            return Err(Errcode::AttachError(1));
+        }
+    };
+    // Every key goes to the terminal of the container, the mode of the terminal of crabcan
+    // is restored when raw is dropped
+    let raw = match state.tty {
+        true => RawTerminal::enable()?,
+        false => None,
+    };
+    let detached = Arc::new(AtomicBool::new(false));
+    let input_detached = detached.clone();
+    thread::spawn(move || send_input(writer, &keys.0, &input_detached));
+    let received = receive_output(socket);
+    drop(raw);
+    if detached.load(Ordering::SeqCst) {
+        log::info!("Detached from container {}", id);
+    }
+    received
+}
+
+// The detach keys are never sent, the keys typed before a part of the sequence are
+fn send_input(mut socket: UnixStream, keys: &[u8], detached: &AtomicBool) {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let mut matched = 0;
+    loop {
+        let size = match read(libc::STDIN_FILENO, &mut buffer) {
+            Ok(0) => {
+                // The container keeps running, only this client stops sending input
+                let _ = socket.shutdown(Shutdown::Write);
+                return;
+            }
+            Ok(size) => size,
+            Err(Errno::EINTR) => continue,
+            Err(_) => return,
+        };
+        let mut input = Vec::with_capacity(size + keys.len());
+        for c in buffer[..size].iter() {
+            if keys.get(matched) != Some(c) {
+                input.extend_from_slice(&keys[..matched]);
+                matched = 0;
+            }
+            if keys.get(matched) != Some(c) {
+                input.push(*c);
+                continue;
+            }
+            matched += 1;
+            if matched == keys.len() {
+                detached.store(true, Ordering::SeqCst);
+                // Ends the output of the client as well
+                let _ = socket.write_all(&input);
+                let _ = socket.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+        if socket.write_all(&input).is_err() {
+            return;
+        }
+    }
+}
+
+// Until the end of the outputs, or until crabcan can't print them anymore
+fn receive_output(mut socket: UnixStream) -> Result<(), Errcode> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut data = Vec::new();
+    loop {
+        match socket.read_exact(&mut header) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => {
+                log::error!("Cannot read the outputs of the container: {}", e);
+                return Err(Errcode::AttachError(2));
+            }
+        }
+        let size = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        data.resize(size, 0);
+        if let Err(e) = socket.read_exact(&mut data) {
+            log::error!("Cannot read the outputs of the container: {}", e);
+            return Err(Errcode::AttachError(2));
+        }
+        let printed = match header[0] {
+            2 => print_output(&mut io::stderr(), &data),
+            _ => print_output(&mut io::stdout(), &data),
+        };
+        if printed.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn print_output(output: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    output.write_all(data)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(s: &str) -> Option<Vec<u8>> {
+        s.parse::<DetachKeys>().ok().map(|keys| keys.0)
+    }
+
+    #[test]
+    fn ctrl_sequences_parse() {
+        assert_eq!(keys("ctrl-p,ctrl-q"), Some(vec![0x10, 0x11]));
+        assert_eq!(keys("ctrl-P,ctrl-Q"), Some(vec![0x10, 0x11]));
+        assert_eq!(keys("ctrl-a"), Some(vec![0x01]));
+        assert_eq!(keys("ctrl-@"), Some(vec![0x00]));
+        assert_eq!(keys("ctrl-["), Some(vec![0x1b]));
+        assert_eq!(keys("ctrl-_"), Some(vec![0x1f]));
+        assert_eq!(keys("ctrl-x,q,ctrl-x"), Some(vec![0x18, b'q', 0x18]));
+    }
+
+    #[test]
+    fn single_characters_parse() {
+        assert_eq!(keys("a"), Some(vec![b'a']));
+        assert_eq!(keys("q,-,~"), Some(vec![b'q', b'-', b'~']));
+    }
+
+    #[test]
+    fn invalid_keys_rejected() {
+        let invalid = [
+            "ctrl-",
+            "ctrl-pq",
+            "ctrl-1",
+            "ctrl-~",
+            "ctrl-é",
+            "ctrl+p",
+            "esc",
+            "é",
+            "ctrl-p,",
+            ",ctrl-p",
+            ",",
+            "ctrl-p,,ctrl-q",
+            "ctrl-p ctrl-q",
+        ];
+        for s in invalid {
+            assert_eq!(keys(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn empty_list_never_detaches() {
+        assert_eq!(keys(""), Some(Vec::new()));
+    }
+}
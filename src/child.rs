@@ -1,79 +1,534 @@
 use crate::capabilities::setcapabilities;
 use crate::config::ContainerOpts;
-use crate::errors::Errcode;
+use crate::errors::{os_error, take_os_error, Errcode};
 use crate::hostname::set_container_hostname;
+use crate::init::run_init;
+use crate::ipc::{recv_message, send_fd, send_message, unexpected, Message};
+use crate::logs::redirect_stdio;
 use crate::mounts::setmountpoint;
-use crate::namespaces::userns;
+use crate::namespaces::{switch_user, userns, NamespaceMode};
+use crate::signals::unblock_signals;
 use crate::syscalls::setsyscalls;
+use crate::tty::attach_terminal;
 
+use crate::resources::add_to_cgroup;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sched::clone;
 use nix::sched::CloneFlags;
-use nix::sys::signal::Signal;
-use nix::unistd::{close, Pid};
+use nix::sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, execve, sysconf, Pid, SysconfVar};
+use std::mem::{size_of, zeroed};
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-//stack size of 1KiB
+//stack size of 1MiB
 const STACK_SIZE: usize = 1024 * 1024;
+// Not defined by nix nor libc yet
+const CLONE_INTO_CGROUP: u64 = 0x200000000;
+// Interval of the checks of a process without pidfd, until it exits
+const EXIT_POLL_DELAY: Duration = Duration::from_millis(100);
+
+// struct clone_args of linux/sched.h, the argument of clone3
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
+
+// How a process ended: its exit code, or the signal which killed it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl ExitStatus {
+    // Like a shell, 128 + the signal if the process was killed
+    pub fn code(self) -> i32 {
+        match self {
+            ExitStatus::Exited(code) => code,
+            ExitStatus::Signaled(signal) => 128 + signal,
+        }
+    }
+}
+
+// The process of the container. When available, its pidfd is used instead of its PID,
+// which could be reused by another process once it is reaped.
+#[derive(Debug)]
+pub struct ChildProcess {
+    pub pid: Pid,
+    pidfd: Option<RawFd>,
+}
+
+impl ChildProcess {
+    // A child created by the spawner, crabcan is its parent
+    pub fn new(pid: Pid, pidfd: Option<RawFd>) -> ChildProcess {
+        ChildProcess { pid, pidfd }
+    }
+
+    // A process crabcan didn't create, it can be signaled and waited for but not reaped
+    pub fn open(pid: Pid) -> ChildProcess {
+        ChildProcess {
+            pid,
+            pidfd: pidfd_open(pid),
+        }
+    }
+
+    // Sent to crabcan by the spawner
+    pub fn pidfd(&self) -> Option<RawFd> {
+        self.pidfd
+    }
+
+    // Another handle on the same process, for another thread
+    pub fn try_clone(&self) -> Result<ChildProcess, Errcode> {
+        let pidfd = match self.pidfd {
+            Some(fd) => match fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0)) {
+                Ok(fd) => Some(fd),
+                Err(e) => {
+                    log::error!("Cannot duplicate the pidfd of the child: {:?}", e);
+                    return Err(Errcode::ChildProcessError(7));
+                }
+            },
+            None => None,
+        };
+        Ok(ChildProcess {
+            pid: self.pid,
+            pidfd,
+        })
+    }
+
+    pub fn signal(&self, signal: Signal) -> Result<(), Errcode> {
+        let res = match self.pidfd {
+            Some(fd) => Errno::result(unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    fd,
+                    signal as libc::c_int,
+                    ptr::null::<libc::siginfo_t>(),
+                    0,
+                )
+            })
+            .map(drop),
+            None => kill(self.pid, signal),
+        };
+        if let Err(e) = res {
+            log::error!("Cannot send {} to the child process: {}", signal, e);
+            return Err(Errcode::ChildProcessError(1));
+        }
+        Ok(())
+    }
+
+    // Returns false if the process still runs after the timeout.
+    // The pidfd becomes readable when the process exits, without a pidfd the process is polled.
+    pub fn wait_exit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.pidfd {
+                Some(fd) => {
+                    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+                    match poll(&mut fds, left.as_millis() as libc::c_int) {
+                        Ok(0) => return false,
+                        Ok(_) => return true,
+                        Err(Errno::EINTR) => continue,
+                        Err(e) => log::debug!("Cannot poll pidfd: {}", e),
+                    }
+                }
+                None if kill(self.pid, None).is_err() => return true,
+                None => {}
+            }
+            if left.is_zero() {
+                return false;
+            }
+            sleep(EXIT_POLL_DELAY.min(left));
+        }
+    }
 
+    // How the process ended, as reported by the kernel
+    pub fn wait(&self) -> Result<ExitStatus, Errcode> {
+        let res = match self.pidfd {
+            Some(fd) => loop {
+                let mut info: libc::siginfo_t = unsafe { zeroed() };
+                let res = Errno::result(unsafe {
+                    libc::waitid(libc::P_PIDFD, fd as libc::id_t, &mut info, libc::WEXITED)
+                });
+                if res != Err(Errno::EINTR) {
+                    break res.map(|_| match info.si_code {
+                        libc::CLD_EXITED => ExitStatus::Exited(unsafe { info.si_status() }),
+                        _ => ExitStatus::Signaled(unsafe { info.si_status() }),
+                    });
+                }
+            },
+            None => loop {
+                match waitpid(self.pid, None) {
+                    Ok(WaitStatus::Exited(_, code)) => break Ok(ExitStatus::Exited(code)),
+                    Ok(WaitStatus::Signaled(_, signal, _)) => {
+                        break Ok(ExitStatus::Signaled(signal as i32))
+                    }
+                    Ok(_) | Err(Errno::EINTR) => continue,
+                    Err(e) => break Err(e),
+                }
+            },
+        };
+        match res {
+            Ok(status) => Ok(status),
+            Err(e) => {
+                log::error!("Error while waiting for pid to finish: {:?}", e);
+                Err(Errcode::ContainerError(1))
+            }
+        }
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        if let Some(fd) = self.pidfd {
+            if let Err(e) = close(fd) {
+                log::error!("Unable to close pidfd: {:?}", e);
+            }
+        }
+    }
+}
+
+// Stack of the child when it is created by clone, on the heap with a guard page below it,
+// so an overflow crashes the child instead of corrupting memory
+struct ChildStack {
+    base: *mut libc::c_void,
+    guard: usize,
+}
+
+impl ChildStack {
+    fn new() -> Result<ChildStack, Errcode> {
+        let guard = match sysconf(SysconfVar::PAGE_SIZE) {
+            Ok(Some(size)) => size as usize,
+            _ => 4096,
+        };
+        let base = match unsafe {
+            mmap(
+                ptr::null_mut(),
+                guard + STACK_SIZE,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_STACK,
+                -1,
+                0,
+            )
+        } {
+            Ok(base) => base,
+            Err(e) => {
+                log::error!("Cannot allocate the stack of the child: {}", e);
+                return Err(os_error(Errcode::ChildProcessError(2), e));
+            }
+        };
+        let stack = ChildStack { base, guard };
+        if let Err(e) = unsafe { mprotect(base, guard, ProtFlags::PROT_NONE) } {
+            log::error!("Cannot protect the stack of the child: {}", e);
+            return Err(os_error(Errcode::ChildProcessError(2), e));
+        }
+        Ok(stack)
+    }
+
+    fn as_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut((self.base as *mut u8).add(self.guard), STACK_SIZE)
+        }
+    }
+}
+
+// The child has its own copy of the memory, the stack isn't needed anymore once it is created
+impl Drop for ChildStack {
+    fn drop(&mut self) {
+        if let Err(e) = unsafe { munmap(self.base, self.guard + STACK_SIZE) } {
+            log::error!("Cannot free the stack of the child: {}", e);
+        }
+    }
+}
+
+// The master of the terminal and the ends of the pipes crabcan reads or writes were closed
+// by the spawner
 fn child(config: ContainerOpts) -> isize {
     match setup_container_configurations(&config) {
         Ok(_) => log::info!("Container set up successfully"),
-        Err(e) => {
-            log::error!("Error while configuring container: {:?}", e);
+        Err(failure) => {
+            log::error!("Error while configuring container: {:?}", failure.errcode);
+            report(config.fd, failure);
             return -1;
         }
     }
 
-    if let Err(_) = close(config.fd) {
-        log::error!("Error while closing socket ...");
+    //the container stays created until the parent tells it to start
+    if let Err(e) = wait_start(config.fd) {
+        log::error!("Container not started: {}", e);
         return -1;
     }
+    if !config.init {
+        return exec_command(&config);
+    }
+    let fds: Vec<RawFd> = std::iter::once(config.fd)
+        .chain(config.tty.map(|pty| pty.slave))
+        .chain(config.stdio.iter().flat_map(|s| s.child_ends()))
+        .collect();
+    match stage("init", run_init(&fds, || exec_command(&config))) {
+        Ok(code) => code,
+        Err(failure) => {
+            log::error!("Error while starting the init: {:?}", failure.errcode);
+            report(config.fd, failure);
+            -1
+        }
+    }
+}
+
+// Only returns if the command couldn't be executed
+fn exec_command(config: &ContainerOpts) -> isize {
+    //logged before the outputs go to the terminal or the log of the container
     log::info!(
         "Starting container with command {} and args {:?}",
         config.path.to_str().unwrap(),
         config.argv
     );
-    0
+    if let Some(pty) = config.tty {
+        if let Err(failure) = stage("tty", attach_terminal(pty.slave)) {
+            report(config.fd, failure);
+            return -1;
+        }
+    }
+    if let Some(stdio) = config.stdio {
+        if let Err(failure) = stage("stdio", redirect_stdio(&stdio)) {
+            report(config.fd, failure);
+            return -1;
+        }
+    }
+    if let Err(failure) = setup_syscalls(config) {
+        log::error!("Error while configuring syscalls: {:?}", failure.errcode);
+        report(config.fd, failure);
+        return -1;
+    }
+
+    //the socket is closed on exec, telling the parent the command started
+    //execve only returns if the command couldn't be executed
+    let Err(e) = execve(&config.path, &config.argv, &config.env);
+    log::error!("Cannot execute {:?}: {:?}", config.path, e);
+    report(
+        config.fd,
+        SetupFailure {
+            stage: "exec",
+            errcode: Errcode::ChildProcessError(3),
+            errno: Some(e),
+        },
+    );
+    -1
 }
 
-pub fn generate_child_process(config: ContainerOpts) -> Result<Pid, Errcode> {
-    //hold the stack of the child process
-    let mut tmp_stack: [u8; STACK_SIZE] = [0; STACK_SIZE];
-    let mut flags = CloneFlags::empty();
+// The child is created inside its cgroup when given the descriptor of its directory.
+// Called by an intermediate process of the spawner, the child is a child of crabcan.
+pub fn generate_child_process(
+    config: ContainerOpts,
+    cgroup: Option<RawFd>,
+) -> Result<ChildProcess, Errcode> {
+    //crabcan waits for the child and reaps it, not the spawner
+    let mut flags = CloneFlags::CLONE_PARENT;
     //insert name spece into the clone flag
     //will start the cloned child in a new mount namespace
     flags.insert(CloneFlags::CLONE_NEWNS);
     //will start the cloned child in a new cgroup namespace
     flags.insert(CloneFlags::CLONE_NEWCGROUP);
-    //will start the cloned child in a new pid namespace
-    flags.insert(CloneFlags::CLONE_NEWPID);
-    //will start the cloned child in a new ipc namespace
-    flags.insert(CloneFlags::CLONE_NEWIPC);
-    //will start the cloned child in a new network namespace
-    flags.insert(CloneFlags::CLONE_NEWNET);
-    //will start the cloned child in a new uts namespace
-    //it will allow the contained process to set its own hostname and NIS domain name in the namespace
-    flags.insert(CloneFlags::CLONE_NEWUTS);
+    //the pid, ipc, network and uts namespaces are new unless shared with the host or another container
+    //a new uts namespace allows the contained process to set its own hostname and NIS domain name
+    flags.insert(config.namespaces.clone_flags());
+    //without privileges, the other namespaces can only be created inside a new user namespace
+    if config.rootless {
+        flags.insert(CloneFlags::CLONE_NEWUSER);
+    }
 
+    //`clone3` creates the child straight in its cgroup, and returns a pidfd along with its PID
+    match clone3(&config, flags, cgroup) {
+        Ok(child) => return Ok(child),
+        //the cgroup isn't on a cgroup2 hierarchy, or the kernel is older than 5.7
+        Err(Errno::EBADF) | Err(Errno::E2BIG) | Err(Errno::EINVAL) if cgroup.is_some() => {
+            log::debug!("Cannot create the child inside its cgroup, moving it there afterwards")
+        }
+        Err(Errno::ENOSYS) => {}
+        Err(e) => {
+            log::error!("Cannot create child process: {}", e);
+            return Err(os_error(Errcode::ChildProcessError(0), e));
+        }
+    }
+    let child = match clone3(&config, flags, None) {
+        Ok(child) => child,
+        Err(Errno::ENOSYS) => {
+            log::debug!("clone3 not supported, using clone");
+            clone_child(&config, flags)?
+        }
+        Err(e) => {
+            log::error!("Cannot create child process: {}", e);
+            return Err(os_error(Errcode::ChildProcessError(0), e));
+        }
+    };
+    //the child runs outside of its cgroup until it is moved there
+    if cgroup.is_some() {
+        add_to_cgroup(&config.hostname, child.pid)?;
+    }
+    Ok(child)
+}
+
+fn clone_child(config: &ContainerOpts, flags: CloneFlags) -> Result<ChildProcess, Errcode> {
     //`clone` create a child process
-    match clone(
+    let mut stack = ChildStack::new()?;
+    let pid = match clone(
         Box::new(|| child(config.clone())),
-        &mut tmp_stack,
+        stack.as_slice(),
         flags,
         Some(Signal::SIGCHLD as i32),
     ) {
-        Ok(pid) => Ok(pid),
-        Err(_) => Err(Errcode::ChildProcessError(0)),
+        Ok(pid) => pid,
+        Err(e) => {
+            log::error!("Cannot create child process: {}", e);
+            return Err(os_error(Errcode::ChildProcessError(0), e));
+        }
+    };
+    Ok(ChildProcess {
+        pid,
+        pidfd: pidfd_open(pid),
+    })
+}
+
+// Without a stack, clone3 duplicates the process like fork
+fn clone3(
+    config: &ContainerOpts,
+    flags: CloneFlags,
+    cgroup: Option<RawFd>,
+) -> Result<ChildProcess, Errno> {
+    let mut pidfd: RawFd = -1;
+    let mut args = CloneArgs {
+        flags: flags.bits() as u64 | libc::CLONE_PIDFD as u64,
+        pidfd: &mut pidfd as *mut RawFd as u64,
+        //with CLONE_PARENT, the exit signal is the one of the spawner, SIGCHLD
+        exit_signal: 0,
+        ..CloneArgs::default()
+    };
+    if let Some(fd) = cgroup {
+        args.flags |= CLONE_INTO_CGROUP;
+        args.cgroup = fd as u64;
+    }
+    let res = Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_clone3,
+            &mut args as *mut CloneArgs,
+            size_of::<CloneArgs>(),
+        )
+    })?;
+    if res == 0 {
+        let code = child(config.clone());
+        unsafe { libc::_exit(code as libc::c_int) }
+    }
+    Ok(ChildProcess {
+        pid: Pid::from_raw(res as i32),
+        pidfd: Some(pidfd),
+    })
+}
+
+// Kernels before 5.3 have no pidfd, the PID is used instead
+fn pidfd_open(pid: Pid) -> Option<RawFd> {
+    match Errno::result(unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) }) {
+        Ok(fd) => Some(fd as RawFd),
+        Err(e) => {
+            log::debug!("pidfd_open not supported: {}", e);
+            None
+        }
     }
 }
 
-fn setup_container_configurations(config: &ContainerOpts) -> Result<(), Errcode> {
-    set_container_hostname(&config.hostname)?;
-    setmountpoint(&config.mount_dir)?;
-    userns(config.fd, config.uid)?;
-    setcapabilities()?;
-    setsyscalls()?;
+// A failed step of the setup, reported to the parent
+pub struct SetupFailure {
+    pub stage: &'static str,
+    pub errcode: Errcode,
+    // None when the step didn't fail on a syscall
+    pub errno: Option<Errno>,
+}
+
+// The OS error is the one recorded where the step failed
+pub fn stage<T>(stage: &'static str, res: Result<T, Errcode>) -> Result<T, SetupFailure> {
+    let errno = take_os_error();
+    res.map_err(|errcode| SetupFailure {
+        stage,
+        errcode,
+        errno,
+    })
+}
+
+pub fn report(fd: RawFd, failure: SetupFailure) {
+    let message = Message::SetupError {
+        stage: failure.stage.to_string(),
+        message: match failure.errno {
+            Some(errno) => errno.desc().to_string(),
+            None => failure.errcode.to_string(),
+        },
+        errcode: failure.errcode,
+        errno: failure.errno.map(|errno| errno as i32),
+    };
+    if let Err(e) = send_message(fd, message) {
+        log::error!("Cannot report the failure to the parent: {}", e);
+    }
+}
+
+fn setup_container_configurations(config: &ContainerOpts) -> Result<(), SetupFailure> {
+    stage("signals", unblock_signals())?;
+    stage("namespaces", config.namespaces.join())?;
+    //in rootless mode, the IDs have to be mapped before creating any file
+    if config.rootless {
+        stage("userns", userns(config.fd, true))?;
+    }
+    //the hostname of the host or of another container is left as it is
+    if config.namespaces.uts == NamespaceMode::Private {
+        stage("hostname", set_container_hostname(&config.hostname))?;
+    }
+    stage("mounts", setmountpoint(&config.mount_dir))?;
+    if !config.rootless {
+        stage("userns", userns(config.fd, false))?;
+    }
+    stage("user", switch_user(&config.user, config.rootless))?;
+    stage("capabilities", setcapabilities())?;
+    Ok(())
+}
+
+// The parent answers Ready with Start, or closes the socket if the container is deleted
+fn wait_start(fd: RawFd) -> Result<(), Errcode> {
+    send_message(fd, Message::Ready)?;
+    match recv_message(fd)? {
+        Some(Message::Start) => Ok(()),
+        message => Err(unexpected(message)),
+    }
+}
+
+// The filter is loaded last, so no syscall of the setup has to be allowed by the profile
+fn setup_syscalls(config: &ContainerOpts) -> Result<(), SetupFailure> {
+    //the parent needs the seccomp listener to answer the notifications
+    if let Some(listener) = stage("seccomp", setsyscalls(&config.seccomp))? {
+        stage("seccomp", send_fd(config.fd, listener))?;
+        if let Err(e) = close(listener) {
+            log::error!("Unable to close seccomp listener: {:?}", e);
+            return stage("seccomp", Err(os_error(Errcode::SyscallsError(7), e)));
+        }
+    }
     Ok(())
 }
 
 //cgroupfs-mount
-//sudo ./target/debug/crabcan  --mount ./ --uid 0 --command bash --debug
+//sudo ./target/debug/crabcan run --mount ./ --uid 0 --command bash --debug
+//without sudo, the container runs in rootless mode
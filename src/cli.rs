@@ -1,6 +1,15 @@
+use crate::attach::DetachKeys;
 use crate::errors::Errcode;
+use crate::logs::parse_since;
+use crate::namespaces::{IdMap, NamespaceMode, PersistNs, TimeOffsets};
+use crate::restart::RestartPolicy;
+use crate::supervisor::Intercept;
 
+use nix::sys::signal::Signal;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
+use std::time::SystemTime;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -10,17 +19,234 @@ pub struct Args {
     #[structopt(short, long)]
     debug: bool,
 
+    #[structopt(subcommand)]
+    pub cmd: Command,
+}
+
+// Parsed once at startup, the size of the run arguments doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Create and start a new container
+    Run(RunArgs),
+
+    /// Set up a new container, its command is executed once started with crabcan start
+    Create(RunArgs),
+
+    /// Start a container set up with crabcan create
+    Start {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Stop a container with its stop signal, killing all its processes after the timeout
+    Stop {
+        /// ID of the container
+        id: String,
+
+        /// Seconds to wait for the container to exit before killing it
+        #[structopt(short, long, default_value = "10")]
+        time: u64,
+
+        /// Signal sent to the process of the container
+        #[structopt(short, long, default_value = "SIGTERM")]
+        signal: Signal,
+    },
+
+    /// Show the status of a container: creating, created, running or stopped
+    State {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Run a command in a running container
+    Exec(ExecArgs),
+
+    /// Print the outputs of a container
+    Logs {
+        /// ID of the container
+        id: String,
+
+        /// Keep printing new outputs until the container is stopped
+        #[structopt(short, long)]
+        follow: bool,
+
+        /// Only print the last lines
+        #[structopt(short = "n", long)]
+        tail: Option<usize>,
+
+        /// Only print the outputs since a time (2024-01-31T12:00:00Z) or for a duration (10m)
+        #[structopt(long, parse(try_from_str = parse_since))]
+        since: Option<SystemTime>,
+    },
+
+    /// Connect to the input and outputs, or the terminal, of a running container
+    Attach {
+        /// ID of the container
+        id: String,
+
+        /// Keys detaching from the container without stopping it (ctrl-<key> or a character)
+        #[structopt(long = "detach-keys", default_value = "ctrl-p,ctrl-q")]
+        detach_keys: DetachKeys,
+    },
+
+    /// Send a signal to the process of a container through its shim
+    Kill {
+        /// ID of the container
+        id: String,
+
+        /// Signal sent to the process of the container
+        #[structopt(default_value = "SIGTERM")]
+        signal: Signal,
+    },
+
+    /// Show the number of processes, CPU time and memory used by a container
+    Stats {
+        /// ID of the container
+        id: String,
+    },
+
+    /// Run a container for crabcan create or --detach, started by crabcan itself
+    #[structopt(setting = AppSettings::Hidden)]
+    Shim {
+        /// Wait for crabcan start before executing the command
+        #[structopt(long)]
+        hold: bool,
+
+        /// Pipe on which the creation of the container is reported
+        #[structopt(long = "notify-fd")]
+        notify_fd: RawFd,
+
+        #[structopt(flatten)]
+        run: RunArgs,
+    },
+
+    /// List the processes of a running container, with their PIDs inside it
+    Ps {
+        /// ID of the container
+        id: String,
+
+        /// Print the processes as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Show the state of a container and a summary of its denied syscalls
+    Inspect {
+        /// ID of the container
+        id: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RunArgs {
     /// Command to execute inside the container
     #[structopt(short, long)]
     pub command: String,
 
-    /// User ID to create inside the container
+    /// User running the command, as user[:group] (names are read from the container rootfs)
+    #[structopt(short, long, alias = "uid")]
+    pub user: String,
+
+    /// Supplementary group of the user (can be repeated)
+    #[structopt(long = "group-add")]
+    pub group_add: Vec<String>,
+
+    /// Network namespace: private, host, container:<id> or ns:<path>. A private namespace
+    /// only has a loopback interface, none is accepted for it
+    #[structopt(long, default_value = "private")]
+    pub net: NamespaceMode,
+
+    /// PID namespace: private, host, container:<id> or ns:<path>
+    #[structopt(long, default_value = "private")]
+    pub pid: NamespaceMode,
+
+    /// IPC namespace: private, host, container:<id> or ns:<path>
+    #[structopt(long, default_value = "private")]
+    pub ipc: NamespaceMode,
+
+    /// UTS namespace: private, host, container:<id> or ns:<path>
+    #[structopt(long, default_value = "private")]
+    pub uts: NamespaceMode,
+
+    /// Run in a time namespace with clock offsets, as monotonic=SECS,boottime=SECS
+    #[structopt(long = "time-offset")]
+    pub time_offset: Option<TimeOffsets>,
+
+    /// Bind-mount a namespace of the container, as <type>[=<path>], to the state directory
+    /// or the given path while it runs (can be repeated)
+    #[structopt(long = "persist-ns")]
+    pub persist_ns: Vec<PersistNs>,
+
+    /// Run an init process as parent of the command, reaping zombies and forwarding signals
+    #[structopt(long)]
+    pub init: bool,
+
+    /// Run the command in a pseudo-terminal, with the terminal of crabcan in raw mode
     #[structopt(short, long)]
-    pub uid: u32,
+    pub tty: bool,
+
+    /// Run the container in the background and print its ID once it is created, crabcan attach connects to it
+    #[structopt(long)]
+    pub detach: bool,
+
+    /// Start the command again once it exited: no, on-failure[:max], always or unless-stopped
+    #[structopt(long, default_value = "no")]
+    pub restart: RestartPolicy,
+
+    /// Unix socket to send the master of the terminal to, instead of using it in crabcan
+    #[structopt(parse(from_os_str), long = "console-socket")]
+    pub console_socket: Option<PathBuf>,
 
     /// Directory to mount as root of the container
     #[structopt(parse(from_os_str), short = "m", long = "mount")]
     pub mount_dir: PathBuf,
+
+    /// Seccomp profile (JSON) to apply instead of the default rules
+    #[structopt(parse(from_os_str), long = "seccomp-profile")]
+    pub seccomp_profile: Option<PathBuf>,
+
+    /// Record the syscalls used by the command and write an allowlist profile to this file
+    #[structopt(parse(from_os_str), long = "seccomp-learn")]
+    pub seccomp_learn: Option<PathBuf>,
+
+    /// Syscall to intercept and handle from the host (can be repeated)
+    #[structopt(long, possible_values = &["mknod", "mount", "sethostname"])]
+    pub intercept: Vec<Intercept>,
+
+    /// Log the syscalls denied by the seccomp profile
+    #[structopt(long = "log-denied")]
+    pub log_denied: bool,
+
+    /// UID mapping inside:outside:count (can be repeated), allocated from /etc/subuid if not set
+    #[structopt(long)]
+    pub uidmap: Vec<IdMap>,
+
+    /// GID mapping inside:outside:count (can be repeated), allocated from /etc/subgid if not set
+    #[structopt(long)]
+    pub gidmap: Vec<IdMap>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExecArgs {
+    /// ID of the container
+    pub id: String,
+
+    /// Run the command in a pseudo-terminal, with the terminal of crabcan in raw mode
+    #[structopt(short, long)]
+    pub tty: bool,
+
+    /// User running the command, as user[:group], the user of the container if not set
+    #[structopt(short, long)]
+    pub user: Option<String>,
+
+    /// Environment variable of the command, as KEY=value (can be repeated)
+    #[structopt(short, long, number_of_values = 1)]
+    pub env: Vec<String>,
+
+    /// Command to execute, with its arguments, after --
+    #[structopt(last = true, required = true)]
+    pub command: Vec<String>,
 }
 
 pub fn parse_args() -> Result<Args, Errcode> {
@@ -37,8 +263,45 @@ pub fn parse_args() -> Result<Args, Errcode> {
 
     // Validate arguments
 
-    if !args.mount_dir.exists() || !args.mount_dir.is_dir() {
-        return Err(Errcode::ArgumentInvalid("mount"));
+    match &args.cmd {
+        Command::Run(run) | Command::Create(run) | Command::Shim { run, .. } => {
+            if !run.mount_dir.exists() || !run.mount_dir.is_dir() {
+                return Err(Errcode::ArgumentInvalid("mount"));
+            }
+
+            if run.seccomp_profile.is_some() && run.seccomp_learn.is_some() {
+                return Err(Errcode::ArgumentInvalid("seccomp-learn"));
+            }
+
+            // While learning, every syscall has to go through without being handled
+            if run.seccomp_learn.is_some() && !run.intercept.is_empty() {
+                return Err(Errcode::ArgumentInvalid("intercept"));
+            }
+
+            if run.seccomp_learn.is_some() && run.log_denied {
+                return Err(Errcode::ArgumentInvalid("log-denied"));
+            }
+
+            // The console socket receives the terminal, a detached crabcan keeps it for
+            // crabcan attach otherwise
+            if run.console_socket.is_some() && !run.tty {
+                return Err(Errcode::ArgumentInvalid("console-socket"));
+            }
+        }
+        Command::Exec(exec) => {
+            if exec.env.iter().any(|e| !e.contains('=')) {
+                return Err(Errcode::ArgumentInvalid("env"));
+            }
+        }
+        Command::Inspect { .. }
+        | Command::Start { .. }
+        | Command::Stop { .. }
+        | Command::State { .. }
+        | Command::Logs { .. }
+        | Command::Attach { .. }
+        | Command::Ps { .. }
+        | Command::Kill { .. }
+        | Command::Stats { .. } => {}
     }
 
     Ok(args)
@@ -2,6 +2,12 @@ use crate::errors::Errcode;
 use crate::hostname::generate_hostname;
 
 use crate::ipc::generate_socket_pair;
+use crate::logs::{open_stdio_pipes, StdioPipes};
+use crate::namespaces::Namespaces;
+use crate::syscalls::SeccompConfig;
+use crate::tty::{open_pty, Pty};
+use crate::user::ContainerUser;
+use nix::unistd::geteuid;
 use std::ffi::CString;
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
@@ -11,35 +17,68 @@ pub struct ContainerOpts {
     // The path of the binary / executable / script to execute inside the container
     pub path: CString,
     pub argv: Vec<CString>,
+    // Environment of the command, as KEY=value
+    pub env: Vec<CString>,
     pub fd: RawFd,
 
-    //The ID of the user inside the container. An ID of 0 means it’s root (administrator)
-    pub uid: u32,
+    //The user inside the container. An ID of 0 means it’s root (administrator)
+    pub user: ContainerUser,
     pub mount_dir: PathBuf,
     pub hostname: String,
+    pub seccomp: SeccompConfig,
+    pub namespaces: Namespaces,
+
+    // Set when crabcan isn't run as root, the user namespace is then created first
+    pub rootless: bool,
+    // The child stays as an init process, parent of the command
+    pub init: bool,
+    // Terminal of the command, if it runs in one
+    pub tty: Option<Pty>,
+    // Input and outputs of the command when it doesn't run in a terminal, logged by crabcan
+    pub stdio: Option<StdioPipes>,
 }
 
 impl ContainerOpts {
+    // Built once from the run arguments
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         command: String,
-        uid: u32,
+        user: ContainerUser,
         mount_dir: PathBuf,
+        seccomp: SeccompConfig,
+        namespaces: Namespaces,
+        init: bool,
+        tty: bool,
+        detach: bool,
     ) -> Result<(ContainerOpts, (RawFd, RawFd)), Errcode> {
         let argv: Vec<CString> = command
             .split_ascii_whitespace()
             .map(|s| CString::new(s).expect("Cannot read arg"))
             .collect();
         let path = argv[0].clone();
+        let env = vec![CString::new(format!("HOME={}", user.home)).expect("Cannot read HOME")];
         let sockets = generate_socket_pair()?;
+        // A detached command has no input but the one of attached clients
+        let (tty, stdio) = match tty {
+            true => (Some(open_pty()?), None),
+            false => (None, Some(open_stdio_pipes(detach)?)),
+        };
 
         Ok((
             ContainerOpts {
                 path,
                 argv,
-                fd: sockets.1.clone(),
-                uid,
+                env,
+                fd: sockets.1,
+                user,
                 mount_dir,
                 hostname: generate_hostname()?,
+                seccomp,
+                namespaces,
+                rootless: !geteuid().is_root(),
+                init,
+                tty,
+                stdio,
             },
             sockets,
         ))
@@ -1,98 +1,677 @@
-use crate::child::generate_child_process;
-use crate::cli::Args;
+use crate::attach::AttachServer;
+use crate::child::{ChildProcess, ExitStatus};
+use crate::cli::RunArgs;
 use crate::config::ContainerOpts;
+use crate::daemon::{daemonize, exec_shim, notify, wait_daemon, Daemon};
 use crate::errors::Errcode;
+use crate::ipc::{
+    create_exec_fifo, generate_socket_pair, open_exec_fifo, recv_fd, recv_message,
+    release_exec_fifo, send_message, unexpected, wait_exec_fifo, Message,
+};
+use crate::logs::{capture, ContainerLog, Stream};
 use crate::mounts::clean_mounts;
-use crate::namespaces::handle_child_uid_map;
-use crate::resources::{restrict_resources, clean_cgroups};
+use crate::namespaces::{
+    allocate_id_maps, check_id_maps, handle_child_uid_map, persist_namespace, release_namespace,
+    resume_child, rootless_id_maps, IdMap, NamespaceMode, Namespaces, PersistNs, NEWGIDMAP,
+    NEWUIDMAP, SUBGID_FILE, SUBUID_FILE,
+};
+use crate::resources::{kill_cgroup, restrict_resources, clean_cgroups};
+use crate::restart::{next_backoff, RestartPolicy};
+use crate::shim::{
+    listen_control, reap_orphans, reap_zombies, remove_control_socket, request, Request,
+};
+use crate::signals::{block_signals, SignalForwarder};
+use crate::spawner::Spawner;
+use crate::state::{
+    container_dir, create_container_dir, exec_fifo, list_containers, lock, ContainerState,
+    ExitReason, Status,
+};
+use crate::supervisor::{
+    supervise, DeniedSyscall, DenyLogHandler, LearnHandler, NotifyHandler, DENIED_LOG,
+};
+use crate::syscalls::{
+    learned_profile, LearnedSyscalls, SeccompConfig, SeccompMode, SeccompProfile, LEARN_ARGS,
+    SECCOMP_PROFILE,
+};
+use crate::tty::{forward_input, send_console, RawTerminal};
+use crate::user::resolve_user;
 
 use nix::sys::utsname::uname;
-use nix::sys::wait::waitpid;
-use nix::unistd::{close, Pid};
+use nix::sys::signal::Signal;
+use nix::unistd::{close, getgid, getuid, Pid};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, remove_file, OpenOptions};
 use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
 
 pub const MINIMAL_KERNAL_VERSION: f32 = 4.8;
+// Delays of crabcan stop, once the stop signal was sent
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(2);
+const CLEANUP_POLL_DELAY: Duration = Duration::from_millis(50);
+// crabcan stop is checked for this often while waiting to restart the command
+const RESTART_POLL_DELAY: Duration = Duration::from_millis(100);
+// First release with time namespaces
+pub const TIME_NS_KERNEL_VERSION: (u32, u32) = (5, 6);
+
+// Architectures for which the seccomp rules can be resolved to syscall numbers
+pub const SUPPORTED_ARCHS: [&str; 6] = [
+    "x86_64", "aarch64", "riscv64", "s390x", "ppc64le", "ppc64",
+];
 
 #[derive(Debug)]
 pub struct Container {
     sockets: (RawFd, RawFd),
     config: ContainerOpts,
-    child_pid: Option<Pid>,
+    child: Option<ChildProcess>,
+    state: ContainerState,
+    seccomp_learn: Option<PathBuf>,
+    learned: LearnedSyscalls,
+    // One for each process of the container, they end with the processes using the filter
+    supervisors: Vec<JoinHandle<()>>,
+    persist_ns: Vec<PersistNs>,
+    console_socket: Option<PathBuf>,
+    // Pipe of a detached crabcan, waiting for the container to be created
+    daemon: Option<RawFd>,
+    log: ContainerLog,
+    // Clients of crabcan attach, none when the terminal is sent to a console socket
+    attach: Option<AttachServer>,
+    restart: RestartPolicy,
+    // Directory of the cgroup, every process of the container is created in it
+    cgroup: Option<RawFd>,
+    // The ends of the terminal and of the pipes given to the child, kept open while the
+    // command may be restarted
+    child_ends_open: bool,
+    // Creates the processes of the container, until the command isn't restarted anymore
+    spawner: Option<Spawner>,
 }
 
 impl Container {
-    pub fn new(args: Args) -> Result<Container, Errcode> {
-        let (config, sockets) = ContainerOpts::new(args.command, args.uid, args.mount_dir)?;
+    pub fn new(args: RunArgs) -> Result<Container, Errcode> {
+        let seccomp = if let Some(path) = &args.seccomp_profile {
+            SeccompMode::Profile(SeccompProfile::from_file(path)?)
+        } else if args.seccomp_learn.is_some() {
+            SeccompMode::Learn
+        } else {
+            SeccompMode::Default
+        };
+        let seccomp = SeccompConfig {
+            mode: seccomp,
+            intercept: args.intercept,
+            log_denied: args.log_denied,
+        };
+        let command = args.command.clone();
+        let user = resolve_user(&args.mount_dir, &args.user, &args.group_add)?;
+        let namespaces = Namespaces {
+            net: args.net,
+            pid: args.pid,
+            ipc: args.ipc,
+            uts: args.uts,
+            time: args.time_offset,
+        }
+        .resolve()?;
+        let (config, sockets) = ContainerOpts::new(
+            args.command,
+            user,
+            args.mount_dir,
+            seccomp,
+            namespaces,
+            args.init,
+            args.tty,
+            args.detach,
+        )?;
+        let mut state = ContainerState::new(&config.hostname, command, config.mount_dir.clone());
+
+        let (own_uid, own_gid) = if config.rootless {
+            // The handlers act on the host and inside the namespaces of the container
+            if !config.seccomp.intercept.is_empty() {
+                log::error!("Intercepting syscalls requires to run crabcan as root");
+                return Err(Errcode::ArgumentInvalid("intercept"));
+            }
+            if !args.persist_ns.is_empty() {
+                log::error!("Persisting namespaces requires to run crabcan as root");
+                return Err(Errcode::ArgumentInvalid("persist-ns"));
+            }
+            if config.namespaces.net == NamespaceMode::Private {
+                log::warn!("Running in rootless mode, the container has no network access");
+            }
+            (Some(getuid().as_raw()), Some(getgid().as_raw()))
+        } else {
+            (None, None)
+        };
+
+        // The ID ranges are reserved by saving the state before releasing the lock
+        let _lock = lock()?;
+        let active: Vec<ContainerState> = list_containers()?
+            .into_iter()
+            .filter(|c| c.is_active())
+            .collect();
+        let taken_uids: Vec<IdMap> = active.iter().flat_map(|c| c.uid_map.clone()).collect();
+        let taken_gids: Vec<IdMap> = active.iter().flat_map(|c| c.gid_map.clone()).collect();
+        state.uid_map = id_maps(args.uidmap, SUBUID_FILE, NEWUIDMAP, own_uid, &taken_uids)?;
+        state.gid_map = id_maps(args.gidmap, SUBGID_FILE, NEWGIDMAP, own_gid, &taken_gids)?;
+
+        let user = &config.user;
+        if !state.uid_map.iter().any(|m| m.contains_inside(user.uid)) {
+            log::error!("UID {} isn't mapped inside the container", user.uid);
+            return Err(Errcode::ArgumentInvalid("user"));
+        }
+        let gids = std::iter::once(&user.gid).chain(user.groups.iter());
+        for gid in gids {
+            if !state.gid_map.iter().any(|m| m.contains_inside(*gid)) {
+                log::error!("GID {} isn't mapped inside the container", gid);
+                return Err(Errcode::ArgumentInvalid("group"));
+            }
+        }
+        state.user = Some(config.user.clone());
+        state.tty = config.tty.is_some();
+        state.save()?;
+        let log = ContainerLog::open(&state.id)?;
+        let attach = match (&args.console_socket, config.tty, config.stdio) {
+            (Some(_), _, _) => None,
+            (None, Some(pty), _) => Some(AttachServer::new(Some(pty.master))?),
+            (None, None, stdio) => Some(AttachServer::new(
+                stdio.and_then(|s| s.stdin).map(|stdin| stdin.1),
+            )?),
+        };
+        // crabcan exec loads it, there is no profile while learning
+        if let Some(profile) = config.seccomp.mode.profile() {
+            profile.to_file(&container_dir(&state.id).join(SECCOMP_PROFILE))?;
+        }
+
         Ok(Container {
             config,
             sockets,
-            child_pid: None,
+            child: None,
+            state,
+            seccomp_learn: args.seccomp_learn,
+            learned: LearnedSyscalls::default(),
+            supervisors: Vec::new(),
+            persist_ns: args.persist_ns,
+            console_socket: args.console_socket,
+            daemon: None,
+            log,
+            attach,
+            restart: args.restart,
+            cgroup: None,
+            child_ends_open: true,
+            spawner: None,
         })
     }
 
-    pub fn create(&mut self) -> Result<(), Errcode> {
-        let pid = generate_child_process(self.config.clone())?;
-        restrict_resources(&self.config.hostname, pid)?;
-        handle_child_uid_map(pid, self.sockets.0)?;
-        //craete the child pid here
-        self.child_pid = Some(pid);
+    // Forked before any thread of crabcan is spawned, without the descriptors only crabcan
+    // uses: the spawner must not keep the socket of a child open, nor the pipe of the daemon
+    fn fork_spawner(&mut self, daemon: Option<RawFd>) -> Result<(), Errcode> {
+        let mut fds = vec![self.sockets.0, self.sockets.1];
+        fds.extend(self.config.tty.map(|pty| pty.master));
+        fds.extend(self.config.stdio.iter().flat_map(|s| s.parent_ends()));
+        fds.extend(daemon);
+        self.spawner = Some(Spawner::fork(&self.config, &fds)?);
+        Ok(())
+    }
+
+    // With hold, the container stays created until crabcan start opens its exec fifo
+    pub fn create(&mut self, hold: bool, signals: &SignalForwarder) -> Result<(), Errcode> {
+        if hold {
+            create_exec_fifo(&exec_fifo(&self.state.id))?;
+        }
+        //the terminal belongs to the program listening on the console socket
+        if let (Some(path), Some(pty)) = (&self.console_socket, self.config.tty) {
+            send_console(path, &pty)?;
+        }
+        self.cgroup = restrict_resources(&self.config.hostname)?;
+        self.spawn(hold, signals)
+    }
+
+    // Creates the process of the container and executes the command in it, the first time
+    // or to restart the command
+    fn spawn(&mut self, hold: bool, signals: &SignalForwarder) -> Result<(), Errcode> {
+        let child = match &self.spawner {
+            Some(spawner) => spawner.spawn(self.sockets.1, self.cgroup),
+            None => {
+                log::error!("The spawner exited, no process can be created anymore");
+                Err(Errcode::ChildProcessError(9))
+            }
+        };
+        // A restarted command gets the same terminal and pipes
+        if self.restart == RestartPolicy::No {
+            self.close_child_ends();
+        }
+        let child = child?;
+        let pid = child.pid;
+        signals.set_child(child.try_clone()?, hold.then(|| exec_fifo(&self.state.id)));
+        //keep the child here, so it can be killed if the creation fails
+        self.child = Some(child);
+        if signals.interrupted() {
+            return Err(Errcode::ContainerError(4));
+        }
+        //only the child keeps its end of the socket, so the parent sees it closing on exec
+        if let Err(e) = close(self.sockets.1) {
+            log::error!("Unable to close read socket: {:?}", e);
+            return Err(Errcode::SocketError(4));
+        }
+        handle_child_uid_map(
+            pid,
+            self.sockets.0,
+            &self.state.uid_map,
+            &self.state.gid_map,
+            self.config.rootless,
+        )?;
+        self.persist_namespaces(pid)?;
+        resume_child(self.sockets.0)?;
+        //a failure of the setup or of execve is reported instead
+        match recv_message(self.sockets.0)? {
+            Some(Message::Ready) => {}
+            message => return Err(unexpected(message)),
+        }
+        self.state.pid = Some(pid.as_raw());
+        if hold {
+            let path = exec_fifo(&self.state.id);
+            let fifo = open_exec_fifo(&path)?;
+            self.state.status = Status::Created;
+            self.state.save()?;
+            log::info!("Container {} created, waiting for crabcan start", self.state.id);
+            self.notify_daemon(Ok(()));
+            wait_exec_fifo(fifo, &path)?;
+            if signals.interrupted() {
+                return Err(Errcode::ContainerError(4));
+            }
+        }
+        send_message(self.sockets.0, Message::Start)?;
+        if self.config.seccomp.needs_supervisor() {
+            let listener = recv_fd(self.sockets.0)?;
+            self.supervisors.push(supervise(listener, self.notify_handlers()?));
+        }
+        match recv_message(self.sockets.0)? {
+            None => log::debug!("Command started in the container"),
+            message => return Err(unexpected(message)),
+        }
+        //the child may have been killed by an interruption instead of executing the command
+        signals.set_started();
+        if signals.interrupted() {
+            return Err(Errcode::ContainerError(4));
+        }
+        self.state.status = Status::Running;
+        self.state.save()?;
+        self.notify_daemon(Ok(()));
         log::debug!("Creation finished");
         Ok(())
     }
 
+    //the output of the terminal ends once the processes of the container closed the slave,
+    //same for the outputs once they closed the pipes
+    fn close_child_ends(&mut self) {
+        //the spawner has copies of them
+        if let Some(spawner) = self.spawner.take() {
+            spawner.close();
+        }
+        if !self.child_ends_open {
+            return;
+        }
+        self.child_ends_open = false;
+        if let Some(pty) = self.config.tty {
+            if let Err(e) = close(pty.slave) {
+                log::error!("Unable to close the slave of the terminal: {:?}", e);
+            }
+            if self.console_socket.is_some() {
+                if let Err(e) = close(pty.master) {
+                    log::error!("Unable to close the master of the terminal: {:?}", e);
+                }
+                self.config.tty = None;
+            }
+        }
+        if let Some(stdio) = self.config.stdio {
+            for fd in stdio.child_ends() {
+                if let Err(e) = close(fd) {
+                    log::error!("Unable to close the pipe of an output: {:?}", e);
+                }
+            }
+        }
+    }
+
+    // Starts the command again in a new process, in the same cgroup and rootfs, with a new
+    // socket to set it up. The threads of crabcan run by now, the process is created by the
+    // spawner like the first one.
+    fn restart(&mut self, signals: &SignalForwarder) -> Result<(), Errcode> {
+        // The namespaces of the previous process are bind-mounted again for the new one
+        for path in self.state.persisted_ns.iter() {
+            release_namespace(path)?;
+        }
+        self.state.persisted_ns.clear();
+        let sockets = generate_socket_pair()?;
+        if let Err(e) = close(self.sockets.0) {
+            log::error!("Unable to close write socket: {:?}", e);
+        }
+        self.sockets = sockets;
+        self.config.fd = sockets.1;
+        self.child = None;
+        self.state.restart_count += 1;
+        self.spawn(false, signals)
+    }
+
+    // Waits for the process of the container, starting the command again as long as the
+    // restart policy asks for it, and returns the exit code of the last process
+    fn wait_restarting(
+        &mut self,
+        signals: &SignalForwarder,
+        detached: bool,
+    ) -> Result<Option<i32>, Errcode> {
+        let mut backoff = None;
+        loop {
+            let started = Instant::now();
+            let status = match wait_child(self.child.as_ref())? {
+                Some(status) => status,
+                None => return Ok(None),
+            };
+            signals.clear_child();
+            let code = status.code();
+            let reason = ExitReason::new(status, signals.stopping());
+            self.state.exit_code = Some(code);
+            self.state.exit_reason = Some(reason);
+            let restarts = self.state.restart_count;
+            if !self.restart.should_restart(reason, restarts, signals.take_signaled()) {
+                return Ok(Some(code));
+            }
+            let delay = next_backoff(backoff, started.elapsed());
+            backoff = Some(delay);
+            log::info!(
+                "Command of container {} exited ({:?}), restarting it in {:?}",
+                self.state.id,
+                reason,
+                delay
+            );
+            self.state.status = Status::Restarting;
+            self.state.save()?;
+            // crabcan stop doesn't wait for the end of the delay
+            let deadline = Instant::now() + delay;
+            loop {
+                if signals.stopping() {
+                    return Ok(Some(code));
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                sleep(RESTART_POLL_DELAY.min(deadline - now));
+            }
+            if let Err(e) = self.restart(signals) {
+                self.kill_child();
+                signals.clear_child();
+                self.state.exit_reason = match signals.interrupted() {
+                    true => Some(ExitReason::Stopped),
+                    false => Some(ExitReason::Failed),
+                };
+                log::error!("Cannot restart container {}: {:?}", self.state.id, e);
+                return Err(e);
+            }
+            if let (true, Some(child)) = (detached, &self.child) {
+                reap_orphans(child.pid);
+            }
+        }
+    }
+
+    // The child must not go on running without its setup, it may already be dead
+    fn kill_child(&self) {
+        if let Some(child) = &self.child {
+            let _ = child.signal(Signal::SIGKILL);
+            if let Err(e) = wait_child(Some(child)) {
+                log::error!("Cannot reap the child: {:?}", e);
+            }
+        }
+    }
+
+    // A detached crabcan returns once the container is created, or failed to be
+    fn notify_daemon(&mut self, result: Result<(), &Errcode>) {
+        if let Some(pipe) = self.daemon.take() {
+            notify(pipe, result.map(|_| self.state.id.as_str()));
+        }
+    }
+
+    // Bind mounts are recorded in the state as soon as they exist, so clean_exit removes them
+    fn persist_namespaces(&mut self, pid: Pid) -> Result<(), Errcode> {
+        for persist in self.persist_ns.iter() {
+            let path = match &persist.path {
+                Some(path) => path.clone(),
+                None => container_dir(&self.state.id).join("ns").join(&persist.ns),
+            };
+            persist_namespace(pid, &persist.ns, &path)?;
+            self.state.persisted_ns.push(path);
+        }
+        Ok(())
+    }
+
+    // Intercepted syscalls are handled first, the last handler takes care of all the others
+    fn notify_handlers(&self) -> Result<Vec<Box<dyn NotifyHandler>>, Errcode> {
+        let seccomp = &self.config.seccomp;
+        let mut handlers: Vec<Box<dyn NotifyHandler>> =
+            seccomp.intercept.iter().map(|i| i.handler()).collect();
+        if let SeccompMode::Learn = seccomp.mode {
+            handlers.push(Box::new(LearnHandler {
+                seen: self.learned.clone(),
+                args: &LEARN_ARGS,
+            }));
+        }
+        if let (true, Some(profile)) = (seccomp.log_denied, seccomp.mode.profile()) {
+            let path = create_container_dir(&self.state.id)?.join(DENIED_LOG);
+            let log = match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(f) => f,
+                Err(e) => {
+                    log::error!("Cannot open {}: {}", path.display(), e);
+                    return Err(Errcode::StateError(5));
+                }
+            };
+            handlers.push(Box::new(DenyLogHandler {
+                errnos: profile.errnos(),
+                default_errno: profile.default_errno(),
+                log,
+            }));
+        }
+        Ok(handlers)
+    }
+
+    // Writes the allowlist built from the syscalls recorded while the container ran
+    pub fn save_learned_profile(&mut self) -> Result<(), Errcode> {
+        for supervisor in self.supervisors.drain(..) {
+            if supervisor.join().is_err() {
+                return Err(Errcode::SyscallsError(8));
+            }
+        }
+        if let Some(path) = &self.seccomp_learn {
+            learned_profile(&self.learned).to_file(path)?;
+            log::info!("Learned seccomp profile written to {}", path.display());
+        }
+        Ok(())
+    }
+
+    // Every step is run even if a previous one failed, the first error is returned
     pub fn clean_exit(&mut self) -> Result<(), Errcode> {
         log::debug!("Cleaning container");
+        let mut result = Ok(());
         if let Err(e) = close(self.sockets.0) {
             log::error!("Unable to close write socket: {:?}", e);
-            return Err(Errcode::SocketError(3));
+            result = result.and(Err(Errcode::SocketError(3)));
         }
 
-        if let Err(e) = close(self.sockets.1) {
-            log::error!("Unable to close read socket: {:?}", e);
-            return Err(Errcode::SocketError(4));
+        // The end of the child is closed by create once the child exists
+        if self.child.is_none() {
+            if let Err(e) = close(self.sockets.1) {
+                log::error!("Unable to close read socket: {:?}", e);
+                result = result.and(Err(Errcode::SocketError(4)));
+            }
+        }
+        if let Some(fd) = self.cgroup.take() {
+            if let Err(e) = close(fd) {
+                log::error!("Unable to close cgroup directory: {:?}", e);
+            }
+        }
+        self.close_child_ends();
+        if let Some(pty) = self.config.tty {
+            if let Err(e) = close(pty.master) {
+                log::error!("Unable to close the master of the terminal: {:?}", e);
+            }
         }
-        clean_mounts(&self.config.mount_dir)?;
+        if let Some(stdio) = self.config.stdio {
+            for fd in stdio.parent_ends() {
+                if let Err(e) = close(fd) {
+                    log::error!("Unable to close the pipe of an output: {:?}", e);
+                }
+            }
+        }
+        result.and(clean_container(&mut self.state))
+    }
+}
 
-        if let Err(e) = clean_cgroups(&self.config.hostname){
-            log::error!("Cgroups cleaning failed: {}", e);
-            return Err(e);
+// Runs the container until its command exits, with hold it waits for crabcan start
+// between the setup and the execution of the command
+pub fn run(args: RunArgs, hold: bool) -> Result<(), Errcode> {
+    check_linux_version(&args)?;
+    if !args.detach && !hold {
+        return run_container(args, false, None);
+    }
+    // The container is run by a shim, crabcan returns once it is created.
+    // A created container is always detached, crabcan start only releases it.
+    match daemonize()? {
+        Daemon::Parent(pipe) => wait_daemon(pipe),
+        Daemon::Daemon(pipe) => {
+            let e = exec_shim(pipe, hold);
+            notify(pipe, Err(&e));
+            Err(e)
         }
-        Ok(())
     }
 }
 
-pub fn start(args: Args) -> Result<(), Errcode> {
-    check_linux_version()?;
-    let mut container = Container::new(args)?;
+// Run by crabcan run, or by the shim of a detached container with the pipe of the daemon
+pub fn run_container(args: RunArgs, hold: bool, daemon: Option<RawFd>) -> Result<(), Errcode> {
+    // The spawner inherits the blocked signals, only crabcan handles them
+    let created = block_signals().and_then(|_| {
+        let mut container = Container::new(args)?;
+        container.fork_spawner(daemon)?;
+        let signals = SignalForwarder::spawn()?;
+        listen_control(&container.state.id, signals.clone(), container.attach.clone())?;
+        Ok((signals, container))
+    });
+    let (signals, mut container) = match created {
+        Ok(created) => created,
+        Err(e) => {
+            if let Some(pipe) = daemon {
+                notify(pipe, Err(&e));
+            }
+            return Err(e);
+        }
+    };
+    container.daemon = daemon;
     log::debug!(
         "Container sockets: ({}, {})",
         container.sockets.0,
         container.sockets.1
     );
-    if let Err(e) = container.create() {
+    if let Err(e) = container.create(hold, &signals) {
+        container.kill_child();
+        signals.clear_child();
+        container.notify_daemon(Err(&e));
         container.clean_exit()?;
         log::error!("Error while creating container: {:?}", e);
         return Err(e);
     }
-    log::debug!("Container child PID: {:?}", container.child_pid);
-    wait_child(container.child_pid)?;
+    log::debug!("Container child PID: {:?}", container.child.as_ref().map(|c| c.pid));
+    let detached = daemon.is_some();
+    if let (true, Some(child)) = (detached, &container.child) {
+        reap_orphans(child.pid);
+    }
+    // The terminal of crabcan is restored when raw is dropped, after the command exited.
+    // Without raw mode, the terminal of the container still works, the error is logged.
+    // A detached crabcan has no input, the terminal only gets the input of crabcan attach.
+    let log = &container.log;
+    let attach = &container.attach;
+    // With a console socket, the master is only kept open for the restarts of the command
+    let (raw, mut output) = match container.config.tty {
+        Some(pty) if container.console_socket.is_none() => {
+            signals.set_tty(pty.master);
+            let raw = match detached {
+                true => None,
+                false => {
+                    forward_input(pty.master);
+                    RawTerminal::enable().unwrap_or(None)
+                }
+            };
+            let stdout = libc::STDOUT_FILENO;
+            (raw, vec![capture(pty.master, stdout, Stream::Stdout, log.clone(), attach.clone())])
+        }
+        _ => (None, Vec::new()),
+    };
+    // Without terminal, the outputs are copied to the outputs of crabcan, to the clients of
+    // crabcan attach and logged, a detached crabcan doesn't have outputs
+    if let Some(pipes) = container.config.stdio {
+        let (stdout, stderr) = (libc::STDOUT_FILENO, libc::STDERR_FILENO);
+        output.push(capture(pipes.stdout.0, stdout, Stream::Stdout, log.clone(), attach.clone()));
+        output.push(capture(pipes.stderr.0, stderr, Stream::Stderr, log.clone(), attach.clone()));
+    }
+    let waited = container.wait_restarting(&signals, detached);
+    signals.clear_child();
+    if detached {
+        reap_zombies();
+    }
+    container.close_child_ends();
+    for thread in output {
+        let _ = thread.join();
+    }
+    if let Some(attach) = &container.attach {
+        attach.close();
+    }
+    drop(raw);
+    match waited {
+        Ok(code) => container.state.exit_code = code,
+        Err(e) => {
+            container.clean_exit()?;
+            return Err(e);
+        }
+    }
+    if let Err(e) = container.save_learned_profile() {
+        container.clean_exit()?;
+        return Err(e);
+    }
     log::debug!("Finished, cleaning & exit");
     container.clean_exit()
 }
 
-pub fn wait_child(pid: Option<Pid>) -> Result<(), Errcode> {
-    if let Some(child_pid) = pid {
-        log::debug!("Waiting for child (pid {}) to finish", child_pid);
+// Returns how the child ended, if there is one
+pub fn wait_child(child: Option<&ChildProcess>) -> Result<Option<ExitStatus>, Errcode> {
+    if let Some(child) = child {
+        log::debug!("Waiting for child (pid {}) to finish", child.pid);
         //wait for state changes in a child of the calling process
-        if let Err(e) = waitpid(child_pid, None) {
-            log::error!("Error while waiting for pid to finish: {:?}", e);
-            return Err(Errcode::ContainerError(1));
-        }
+        return child.wait().map(Some);
+    }
+    Ok(None)
+}
+
+// Explicit maps only need to be free, otherwise a free range is allocated.
+// In rootless mode, own_id is the ID of the user, which all of its containers share.
+fn id_maps(
+    maps: Vec<IdMap>,
+    subid_file: &str,
+    helper: &str,
+    own_id: Option<u32>,
+    taken: &[IdMap],
+) -> Result<Vec<IdMap>, Errcode> {
+    let taken: Vec<IdMap> = taken
+        .iter()
+        .filter(|m| m.count != 1 || Some(m.outside) != own_id)
+        .cloned()
+        .collect();
+    if !maps.is_empty() {
+        check_id_maps(&maps, &taken)?;
+        return Ok(maps);
+    }
+    match own_id {
+        Some(id) => Ok(rootless_id_maps(subid_file, helper, id, &taken)),
+        None => allocate_id_maps(subid_file, &taken),
     }
-    Ok(())
 }
 
-pub fn check_linux_version() -> Result<(), Errcode> {
+pub fn check_linux_version(args: &RunArgs) -> Result<(), Errcode> {
     let host = uname();
     log::debug!("Linux release: {}", host.release());
 
@@ -105,9 +684,216 @@ pub fn check_linux_version() -> Result<(), Errcode> {
         return Err(Errcode::ContainerError(0));
     }
 
-    if host.machine() != "x86_64" {
+    // Releases are compared as numbers, 5.10 comes after 5.6
+    if args.time_offset.is_some() {
+        match scan_fmt!(host.release(), "{d}.{d}", u32, u32) {
+            Ok(version) if version >= TIME_NS_KERNEL_VERSION => {}
+            _ => {
+                log::error!(
+                    "Time namespaces require Linux {}.{} or later",
+                    TIME_NS_KERNEL_VERSION.0,
+                    TIME_NS_KERNEL_VERSION.1
+                );
+                return Err(Errcode::NotSupported(2));
+            }
+        }
+    }
+
+    if !SUPPORTED_ARCHS.contains(&host.machine()) {
+        log::error!("Architecture {} is not supported", host.machine());
         return Err(Errcode::NotSupported(1));
     }
 
     Ok(())
 }
+
+// Releases the resources of a container whose process exited, done by the crabcan process
+// running it, or by crabcan stop if that process is gone.
+// Every step is run even if a previous one failed, the first error is returned.
+fn clean_container(state: &mut ContainerState) -> Result<(), Errcode> {
+    let mut result = Ok(());
+    if let Err(e) = clean_mounts(&state.rootfs) {
+        result = result.and(Err(e));
+    }
+
+    if let Err(e) = clean_cgroups(&state.id) {
+        log::error!("Cgroups cleaning failed: {}", e);
+        result = result.and(Err(e));
+    }
+
+    for path in state.persisted_ns.iter() {
+        if let Err(e) = release_namespace(path) {
+            result = result.and(Err(e));
+        }
+    }
+    state.persisted_ns.clear();
+
+    // Left behind if the container is stopped before being started
+    let fifo = exec_fifo(&state.id);
+    if fifo.exists() {
+        if let Err(e) = remove_file(&fifo) {
+            log::error!("Cannot remove exec fifo {}: {}", fifo.display(), e);
+        }
+    }
+
+    remove_control_socket(&state.id);
+
+    // The state directory is kept, so the container can still be inspected,
+    // and its ID ranges are free again once it is stopped
+    state.status = Status::Stopped;
+    let saved = state.save();
+    result.and(saved)
+}
+
+// Sends the stop signal to the process of the container, its init when it has one,
+// and kills every process of the container if it didn't exit before the timeout
+pub fn stop(id: &str, timeout: u64, signal: Signal) -> Result<(), Errcode> {
+    let mut state = ContainerState::load(id)?;
+    if !state.is_active() {
+        if state.status == Status::Stopped {
+            log::info!("Container {} is already stopped", id);
+            return Ok(());
+        }
+        log::warn!("Process of container {} exited without being cleaned up", id);
+        return clean_container(&mut state);
+    }
+    // The crabcan process running the container doesn't restart its command anymore,
+    // it may have restarted it since the state was loaded
+    if request(id, &Request::Stop).is_ok() {
+        state = ContainerState::load(id)?;
+    }
+    // Between two runs of the command, there is no process to stop
+    if state.status == Status::Restarting {
+        return wait_cleanup(id);
+    }
+    let process = match state.pid {
+        Some(pid) => ChildProcess::open(Pid::from_raw(pid)),
+        None => {
+            log::error!("Container {} has no process yet", id);
+            return Err(Errcode::ContainerError(5));
+        }
+    };
+
+    // Until it is started, the process can't handle the signal
+    let mut exited = false;
+    if state.status == Status::Running {
+        process.signal(signal)?;
+        exited = process.wait_exit(Duration::from_secs(timeout));
+    }
+    if !exited {
+        log::info!("Killing every process of container {}", id);
+        kill_cgroup(id)?;
+        // Without cgroup, the processes are killed with the PID namespace of the container
+        let _ = process.signal(Signal::SIGKILL);
+        if !process.wait_exit(KILL_TIMEOUT) {
+            log::error!("Process of container {} still runs after SIGKILL", id);
+            return Err(Errcode::ContainerError(6));
+        }
+    }
+    // A created container isn't waiting for crabcan start anymore
+    if state.status == Status::Created {
+        let _ = release_exec_fifo(&exec_fifo(id));
+    }
+    wait_cleanup(id)
+}
+
+// The crabcan process running the container cleans it up once it reaped its process
+fn wait_cleanup(id: &str) -> Result<(), Errcode> {
+    let deadline = Instant::now() + CLEANUP_TIMEOUT;
+    while Instant::now() < deadline {
+        let state = ContainerState::load(id)?;
+        if state.status == Status::Stopped {
+            return Ok(());
+        }
+        sleep(CLEANUP_POLL_DELAY);
+    }
+    log::warn!("Container {} wasn't cleaned up by its crabcan process", id);
+    let mut state = ContainerState::load(id)?;
+    clean_container(&mut state)
+}
+
+// Releases a container created with crabcan create, the command runs in the
+// process of crabcan create
+pub fn start(id: &str) -> Result<(), Errcode> {
+    let state = ContainerState::load(id)?;
+    if state.status != Status::Created || !state.is_active() {
+        log::error!("Container {} isn't waiting to be started", id);
+        return Err(Errcode::ContainerError(2));
+    }
+    release_exec_fifo(&exec_fifo(id))
+}
+
+pub fn state(id: &str) -> Result<(), Errcode> {
+    let state = ContainerState::load(id)?;
+    // A container whose process died without crabcan noticing it is stopped
+    let status = match state.is_active() {
+        true => state.status,
+        false => Status::Stopped,
+    };
+    let report = StateReport {
+        id: &state.id,
+        status,
+        pid: state.pid,
+        rootfs: &state.rootfs,
+        created: state.created,
+        exit_code: state.exit_code,
+    };
+    match serde_json::to_string_pretty(&report) {
+        Ok(out) => {
+            println!("{}", out);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Cannot serialize container {}: {}", id, e);
+            Err(Errcode::StateError(2))
+        }
+    }
+}
+
+// The short state of a container, as other runtimes print it
+#[derive(Debug, Serialize)]
+struct StateReport<'a> {
+    id: &'a str,
+    status: Status,
+    pid: Option<i32>,
+    rootfs: &'a PathBuf,
+    created: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Inspect {
+    #[serde(flatten)]
+    state: ContainerState,
+    // Number of times each syscall was denied, when denied syscalls are logged
+    denied_syscalls: BTreeMap<String, usize>,
+}
+
+pub fn inspect(id: &str) -> Result<(), Errcode> {
+    let state = ContainerState::load(id)?;
+    let mut denied_syscalls = BTreeMap::new();
+    if let Ok(log) = read_to_string(container_dir(id).join(DENIED_LOG)) {
+        for line in log.lines() {
+            match serde_json::from_str::<DeniedSyscall>(line) {
+                Ok(denied) => *denied_syscalls.entry(denied.syscall).or_insert(0) += 1,
+                Err(e) => log::error!("Invalid line in log of denied syscalls: {}", e),
+            }
+        }
+    }
+
+    let inspect = Inspect {
+        state,
+        denied_syscalls,
+    };
+    match serde_json::to_string_pretty(&inspect) {
+        Ok(out) => {
+            println!("{}", out);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Cannot serialize container {}: {}", id, e);
+            Err(Errcode::StateError(2))
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::errors::Errcode;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag};
+use nix::sys::stat::Mode;
+use nix::sys::wait::waitpid;
+use nix::unistd::{close, dup2, execv, fork, pipe2, read, setsid, write, ForkResult};
+use std::env;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+
+// The executable of the running crabcan, even if its path changed since
+const SELF_EXE: &str = "/proc/self/exe";
+
+// Process running the container once crabcan is detached from it
+pub enum Daemon {
+    // The process started by the user, with the pipe on which the daemon reports
+    Parent(RawFd),
+    // The daemon, with the pipe to report on
+    Daemon(RawFd),
+}
+
+// Double fork: the daemon is in its own session and reparented to init (or to a subreaper)
+// as soon as the intermediate process exits, so it outlives crabcan.
+// Must be called before any thread is spawned, only the forking thread is copied.
+pub fn daemonize() -> Result<Daemon, Errcode> {
+    let (read_end, write_end) = match pipe2(OFlag::O_CLOEXEC) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            log::error!("Cannot create the pipe of the daemon: {:?}", e);
+            return Err(Errcode::ContainerError(7));
+        }
+    };
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { child }) => {
+            let _ = close(write_end);
+            // The intermediate process exits right after forking the daemon
+            if let Err(e) = waitpid(child, None) {
+                log::error!("Cannot wait for the intermediate process: {:?}", e);
+            }
+            return Ok(Daemon::Parent(read_end));
+        }
+        Ok(ForkResult::Child) => {}
+        Err(e) => {
+            log::error!("Cannot fork the daemon: {:?}", e);
+            return Err(Errcode::ContainerError(7));
+        }
+    }
+
+    let _ = close(read_end);
+    if let Err(e) = setsid() {
+        log::error!("Cannot create the session of the daemon: {:?}", e);
+        unsafe { libc::_exit(1) }
+    }
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { .. }) => unsafe { libc::_exit(0) },
+        Ok(ForkResult::Child) => {}
+        Err(e) => {
+            log::error!("Cannot fork the daemon: {:?}", e);
+            notify(write_end, Err(&Errcode::ContainerError(7)));
+            unsafe { libc::_exit(1) }
+        }
+    }
+
+    // The daemon and the container must not keep the terminal or the pipes of the caller,
+    // which would wait for them to be closed. Creation errors are reported on the pipe.
+    match open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+        Ok(null) => {
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                if let Err(e) = dup2(null, fd) {
+                    log::error!("Cannot redirect fd {} of the daemon: {:?}", fd, e);
+                }
+            }
+            let _ = close(null);
+        }
+        Err(e) => log::error!("Cannot open /dev/null: {:?}", e),
+    }
+    Ok(Daemon::Daemon(write_end))
+}
+
+// The daemon runs the container as crabcan shim, a new image of crabcan without the
+// state of the process started by the user. The arguments are the ones of crabcan run
+// or create, the pipe stays open across the execution.
+pub fn exec_shim(pipe: RawFd, hold: bool) -> Errcode {
+    if let Err(e) = fcntl(pipe, FcntlArg::F_SETFD(FdFlag::empty())) {
+        log::error!("Cannot keep the pipe of the daemon open: {:?}", e);
+        return Errcode::ContainerError(7);
+    }
+    let mut args: Vec<CString> = env::args_os()
+        .map(|arg| CString::new(arg.as_bytes()).expect("Cannot read arg"))
+        .collect();
+    // The subcommand is the first argument which isn't an option, crabcan only has flags
+    let position = match args
+        .iter()
+        .skip(1)
+        .position(|a| !a.as_bytes().starts_with(b"-"))
+    {
+        Some(position) => position + 1,
+        None => return Errcode::ContainerError(7),
+    };
+    let mut shim = vec![
+        CString::new("shim").expect("Cannot read arg"),
+        CString::new(format!("--notify-fd={}", pipe)).expect("Cannot read arg"),
+    ];
+    if hold {
+        shim.push(CString::new("--hold").expect("Cannot read arg"));
+    }
+    args.splice(position..=position, shim);
+    let Err(e) = execv(&CString::new(SELF_EXE).expect("Cannot read path"), &args);
+    log::error!("Cannot execute the shim: {:?}", e);
+    Errcode::ContainerError(7)
+}
+
+// Tells the parent the container is created, with its ID, or why it couldn't be.
+// The message ends with a newline: the child of the container may still have the pipe
+// open, the parent can't wait for its end.
+pub fn notify(pipe: RawFd, result: Result<&str, &Errcode>) {
+    match serde_json::to_vec(&result) {
+        Ok(mut message) => {
+            message.push(b'\n');
+            let mut written = 0;
+            while written < message.len() {
+                match write(pipe, &message[written..]) {
+                    Ok(n) => written += n,
+                    Err(Errno::EINTR) => continue,
+                    Err(e) => {
+                        log::error!("Cannot notify the parent of the daemon: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        Err(e) => log::error!("Cannot serialize the result of the daemon: {}", e),
+    }
+    let _ = close(pipe);
+}
+
+// The ID of the container is printed once the daemon created it
+pub fn wait_daemon(pipe: RawFd) -> Result<(), Errcode> {
+    let mut message = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !message.ends_with(b"\n") {
+        match read(pipe, &mut buffer) {
+            Ok(0) => break,
+            Ok(n) => message.extend_from_slice(&buffer[..n]),
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                log::error!("Cannot read the result of the daemon: {:?}", e);
+                break;
+            }
+        }
+    }
+    let _ = close(pipe);
+    match serde_json::from_slice::<Result<String, Errcode>>(&message) {
+        Ok(Ok(id)) => {
+            println!("{}", id);
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            log::error!("Error while creating container: {:?}", e);
+            Err(e)
+        }
+        Err(_) => {
+            log::error!("The daemon exited without creating the container");
+            Err(Errcode::ContainerError(7))
+        }
+    }
+}
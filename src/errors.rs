@@ -1,10 +1,18 @@
+use nix::errno::Errno;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cell::Cell;
 use std::fmt;
+use std::io;
 use std::process::exit;
 
-#[derive(Debug)]
+// Behind an alias, serde doesn't try to borrow the name from the input
+type ArgumentName = &'static str;
+
+#[derive(Debug, Serialize, Deserialize)]
 // Contains all possible errors in our tool
+// They are serialized when the child reports a failure to the parent
 pub enum Errcode {
-    ArgumentInvalid(&'static str),
+    ArgumentInvalid(#[serde(deserialize_with = "leak_str")] ArgumentName),
     ContainerError(u8),
     ChildProcessError(u8),
     NotSupported(u8),
@@ -16,6 +24,12 @@ pub enum Errcode {
     CapabilitiesError(u8),
     SyscallsError(u8),
     ResourcesError(u8),
+    StateError(u8),
+    TtyError(u8),
+    LogsError(u8),
+    AttachError(u8),
+    PsError(u8),
+    ShimError(u8),
 }
 
 #[allow(unreachable_patterns)]
@@ -38,6 +52,37 @@ impl Errcode {
     }
 }
 
+thread_local! {
+    // Errno of the failed syscall behind the last error, until the setup step reports it
+    static OS_ERROR: Cell<Option<Errno>> = const { Cell::new(None) };
+}
+
+// The error of a failed syscall. Its errno is kept where it happens, the one of the thread
+// is overwritten by the calls made until the failure is reported to the parent.
+pub fn os_error(errcode: Errcode, errno: Errno) -> Errcode {
+    OS_ERROR.with(|e| e.set(Some(errno)));
+    errcode
+}
+
+pub fn io_error(errcode: Errcode, error: &io::Error) -> Errcode {
+    match error.raw_os_error() {
+        Some(errno) => os_error(errcode, Errno::from_i32(errno)),
+        None => errcode,
+    }
+}
+
+// None if the last error didn't come from a syscall
+pub fn take_os_error() -> Option<Errno> {
+    OS_ERROR.with(|e| e.take())
+}
+
+// An error is received at most once from the child, leaking its argument name is fine
+fn leak_str<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static str, D::Error> {
+    Ok(Box::leak(
+        String::deserialize(deserializer)?.into_boxed_str(),
+    ))
+}
+
 pub fn exit_with_retcode(res: Result<(), Errcode>) {
     match res {
         Ok(_) => {
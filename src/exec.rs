@@ -0,0 +1,217 @@
+use crate::capabilities::setcapabilities;
+use crate::child::{report, stage, ChildProcess, SetupFailure};
+use crate::cli::ExecArgs;
+use crate::errors::Errcode;
+use crate::init::run_init;
+use crate::ipc::{generate_socket_pair, recv_message, unexpected};
+use crate::namespaces::{enter_namespaces, switch_user};
+use crate::resources::{join_cgroup, set_rlimits};
+use crate::signals::{block_signals, unblock_signals, SignalForwarder};
+use crate::state::{container_dir, ContainerState, Status};
+use crate::syscalls::{setsyscalls, SeccompConfig, SeccompMode, SeccompProfile, SECCOMP_PROFILE};
+use crate::tty::{attach_terminal, open_pty, proxy_terminal, Pty, RawTerminal};
+use crate::user::{resolve_user, ContainerUser};
+
+use nix::sys::signal::Signal;
+use nix::unistd::{chdir, close, execve, fork, geteuid, ForkResult, Pid};
+use std::ffi::CString;
+use std::iter;
+use std::os::unix::io::RawFd;
+
+// What the command run by crabcan exec needs once in the container
+struct ExecConfig {
+    path: CString,
+    argv: Vec<CString>,
+    env: Vec<CString>,
+    // A failure is reported on the socket, which is closed when the command is executed
+    fd: RawFd,
+    user: ContainerUser,
+    rootless: bool,
+    seccomp: SeccompConfig,
+    tty: Option<Pty>,
+}
+
+// Runs a command next to the process of a running container. A child of crabcan joins the
+// cgroup and the namespaces of the container, then forks the command like an init, so it
+// enters the PID namespace as well. The command gets the same user, capabilities, rlimits
+// and seccomp filter as the process of the container.
+pub fn exec(args: ExecArgs) -> Result<(), Errcode> {
+    let state = ContainerState::load(&args.id)?;
+    let pid = match state.pid {
+        Some(pid) if state.status == Status::Running && state.is_active() => Pid::from_raw(pid),
+        _ => {
+            log::error!("Container {} isn't running", args.id);
+            return Err(Errcode::ContainerError(8));
+        }
+    };
+    let user = match (&args.user, &state.user) {
+        (Some(user), _) => resolve_user(&state.rootfs, user, &[])?,
+        (None, Some(user)) => user.clone(),
+        (None, None) => {
+            log::error!("Container {} has no user to run the command as", args.id);
+            return Err(Errcode::ArgumentInvalid("user"));
+        }
+    };
+    // The syscalls intercepted or logged for the container are handled by its crabcan
+    // process, only the profile applies to the command
+    let profile = container_dir(&args.id).join(SECCOMP_PROFILE);
+    if !profile.exists() {
+        log::error!("Container {} has no seccomp profile to apply", args.id);
+        return Err(Errcode::SyscallsError(11));
+    }
+    let seccomp = SeccompConfig {
+        mode: SeccompMode::Profile(SeccompProfile::from_file(&profile)?),
+        intercept: Vec::new(),
+        log_denied: false,
+    };
+
+    let argv: Vec<CString> = args
+        .command
+        .iter()
+        .map(|s| CString::new(s.as_str()).expect("Cannot read arg"))
+        .collect();
+    let env = iter::once(format!("HOME={}", user.home))
+        .chain(args.env.iter().cloned())
+        .map(|e| CString::new(e).expect("Cannot read env"))
+        .collect();
+    // The child is forked before the forwarder thread is spawned, so it can't copy a lock
+    // held by the thread. The signals are blocked already, they wait for the forwarder.
+    block_signals()?;
+    let sockets = generate_socket_pair()?;
+    let tty = match args.tty {
+        true => Some(open_pty()?),
+        false => None,
+    };
+    let config = ExecConfig {
+        path: argv[0].clone(),
+        argv,
+        env,
+        fd: sockets.1,
+        user,
+        rootless: !geteuid().is_root(),
+        seccomp,
+        tty,
+    };
+
+    let child = match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let code = enter_container(&state.id, pid, &config, sockets.0);
+            unsafe { libc::_exit(code as libc::c_int) }
+        }
+        Ok(ForkResult::Parent { child }) => ChildProcess::open(child),
+        Err(e) => {
+            log::error!("Cannot fork the process to execute: {:?}", e);
+            return Err(Errcode::ChildProcessError(0));
+        }
+    };
+    let _ = close(sockets.1);
+    if let Some(pty) = tty {
+        let _ = close(pty.slave);
+    }
+    let signals = match SignalForwarder::spawn() {
+        Ok(signals) => signals,
+        Err(e) => {
+            let _ = child.signal(Signal::SIGKILL);
+            let _ = child.wait();
+            let _ = close(sockets.0);
+            if let Some(pty) = tty {
+                let _ = close(pty.master);
+            }
+            return Err(e);
+        }
+    };
+    signals.set_child(child.try_clone()?, None);
+
+    // The socket is closed once the command is executed, a failure is reported before
+    let started = match recv_message(sockets.0) {
+        Ok(None) => {
+            signals.set_started();
+            Ok(())
+        }
+        Ok(message) => Err(unexpected(message)),
+        Err(e) => Err(e),
+    };
+    let (raw, output) = match (&started, tty) {
+        (Ok(()), Some(pty)) => {
+            signals.set_tty(pty.master);
+            let raw = RawTerminal::enable().unwrap_or(None);
+            (raw, Some(proxy_terminal(pty.master)))
+        }
+        _ => (None, None),
+    };
+    let waited = child.wait();
+    signals.clear_child();
+    if let Some(output) = output {
+        let _ = output.join();
+    }
+    drop(raw);
+    let _ = close(sockets.0);
+    if let Some(pty) = tty {
+        let _ = close(pty.master);
+    }
+    if signals.interrupted() {
+        return Err(Errcode::ContainerError(4));
+    }
+    started.and(waited.map(drop))
+}
+
+// Runs in the child of crabcan, which stays as the parent of the command
+fn enter_container(id: &str, pid: Pid, config: &ExecConfig, socket: RawFd) -> isize {
+    let _ = close(socket);
+    if let Some(pty) = config.tty {
+        let _ = close(pty.master);
+    }
+    let entered =
+        stage("cgroup", join_cgroup(id)).and_then(|_| stage("namespaces", enter_namespaces(pid)));
+    if let Err(failure) = entered {
+        report(config.fd, failure);
+        return -1;
+    }
+    let fds: Vec<RawFd> = iter::once(config.fd)
+        .chain(config.tty.map(|pty| pty.slave))
+        .collect();
+    match stage("init", run_init(&fds, || exec_process(config))) {
+        Ok(code) => code,
+        Err(failure) => {
+            report(config.fd, failure);
+            -1
+        }
+    }
+}
+
+// Only returns if the command couldn't be executed
+fn exec_process(config: &ExecConfig) -> isize {
+    if let Err(failure) = setup_process(config) {
+        report(config.fd, failure);
+        return -1;
+    }
+    let Err(e) = execve(&config.path, &config.argv, &config.env);
+    report(
+        config.fd,
+        SetupFailure {
+            stage: "exec",
+            errcode: Errcode::ChildProcessError(3),
+            errno: Some(e),
+        },
+    );
+    -1
+}
+
+// The same steps as the setup of the process of the container, which gets its rlimits
+// from crabcan
+fn setup_process(config: &ExecConfig) -> Result<(), SetupFailure> {
+    stage("signals", unblock_signals())?;
+    stage("rlimits", set_rlimits())?;
+    // The root of the mount namespace is the rootfs of the container
+    if chdir("/").is_err() {
+        return stage("root", Err(Errcode::MountsError(5)));
+    }
+    if let Some(pty) = config.tty {
+        stage("tty", attach_terminal(pty.slave))?;
+    }
+    stage("user", switch_user(&config.user, config.rootless))?;
+    stage("capabilities", setcapabilities())?;
+    // no_new_privs is set when the filter is loaded, as for the process of the container
+    stage("seccomp", setsyscalls(&config.seccomp))?;
+    Ok(())
+}
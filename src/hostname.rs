@@ -1,4 +1,4 @@
-use crate::errors::Errcode;
+use crate::errors::{os_error, Errcode};
 
 use nix::unistd::sethostname;
 use rand::seq::SliceRandom;
@@ -41,9 +41,9 @@ pub fn set_container_hostname(hostname: &String) -> Result<(), Errcode> {
             log::debug!("Container hostname is now {}", hostname);
             Ok(())
         }
-        Err(_) => {
+        Err(e) => {
             log::error!("Cannot set hostname {} for container", hostname);
-            Err(Errcode::HostnameError(0))
+            Err(os_error(Errcode::HostnameError(0), e))
         }
     }
 }
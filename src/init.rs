@@ -0,0 +1,118 @@
+use crate::errors::{os_error, Errcode};
+
+use nix::errno::Errno;
+use nix::sys::signal::{sigprocmask, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{close, fork, ForkResult, Pid};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// Signals raised by a fault of the init itself, or stopping it when it touches a terminal,
+// they keep their default action instead of being forwarded
+const UNFORWARDED_SIGNALS: [Signal; 9] = [
+    Signal::SIGFPE,
+    Signal::SIGILL,
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGABRT,
+    Signal::SIGTRAP,
+    Signal::SIGSYS,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+// Like tini, the init forks the workload and stays as its parent: it forwards the signals
+// it receives to the workload and reaps every process reparented to it. It exits with the
+// status of the workload, 128 + the signal number if it was killed by a signal.
+// Only the workload keeps the socket, which is closed when its command is executed,
+// and the terminal, fds are closed in the init once the workload is forked.
+pub fn run_init(fds: &[RawFd], workload: impl FnOnce() -> isize) -> Result<isize, Errcode> {
+    // Blocked before forking, so no signal can get lost in between.
+    // The PID 1 of a namespace only receives the signals it handles or blocks.
+    let mut signals = SigSet::all();
+    for signal in UNFORWARDED_SIGNALS.iter() {
+        signals.remove(*signal);
+    }
+    let mut previous = SigSet::empty();
+    if let Err(e) = sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), Some(&mut previous)) {
+        log::error!("Cannot block signals in the init: {:?}", e);
+        return Err(os_error(Errcode::ChildProcessError(4), e));
+    }
+    // Orphans are reparented to the init even when it isn't the PID 1 of the container
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        let e = Errno::last();
+        log::error!("Cannot make the init a subreaper: {:?}", e);
+        return Err(os_error(Errcode::ChildProcessError(4), e));
+    }
+
+    let pid = match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            if let Err(e) = sigprocmask(SigmaskHow::SIG_SETMASK, Some(&previous), None) {
+                log::error!("Cannot restore the signal mask of the workload: {:?}", e);
+                unsafe { libc::_exit(-1) }
+            }
+            let code = workload();
+            unsafe { libc::_exit(code as libc::c_int) }
+        }
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => {
+            log::error!("Cannot fork the workload: {:?}", e);
+            return Err(os_error(Errcode::ChildProcessError(5), e));
+        }
+    };
+    log::debug!("Init forwarding signals to workload {}", pid);
+
+    for fd in fds.iter() {
+        if let Err(e) = close(*fd) {
+            log::error!("Unable to close fd {} in the init: {:?}", fd, e);
+        }
+    }
+    // A process of the container running as the same user can't ptrace the init,
+    // which doesn't have the seccomp filter of the workload
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        log::error!("Cannot make the init non dumpable: {:?}", Errno::last());
+    }
+    Ok(forward_signals(pid, &signals))
+}
+
+fn forward_signals(workload: Pid, signals: &SigSet) -> isize {
+    loop {
+        // Real-time signals are forwarded too, they have no variant in Signal
+        let signal = unsafe { libc::sigwaitinfo(signals.as_ref(), ptr::null_mut()) };
+        if signal < 0 {
+            match Errno::last() {
+                Errno::EINTR => continue,
+                e => {
+                    log::error!("Cannot wait for signals in the init: {:?}", e);
+                    return -1;
+                }
+            }
+        }
+        if signal != libc::SIGCHLD {
+            if unsafe { libc::kill(workload.as_raw(), signal) } != 0 {
+                log::debug!("Cannot forward signal {}: {:?}", signal, Errno::last());
+            }
+            continue;
+        }
+        if let Some(code) = reap_children(workload) {
+            return code;
+        }
+    }
+}
+
+// Reaps every exited child, returns the exit code of the workload if it is one of them
+fn reap_children(workload: Pid) -> Option<isize> {
+    let mut code = None;
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(pid, status)) if pid == workload => code = Some(status as isize),
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == workload => {
+                code = Some(128 + signal as isize)
+            }
+            Ok(WaitStatus::StillAlive) => return code,
+            Ok(_) | Err(Errno::EINTR) => continue,
+            // No child left
+            Err(_) => return code,
+        }
+    }
+}
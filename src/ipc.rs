@@ -1,7 +1,73 @@
-use crate::errors::Errcode;
+use crate::errors::{os_error, Errcode};
 
-use nix::sys::socket::{recv, send, socketpair, AddressFamily, MsgFlags, SockFlag, SockType};
-use std::os::unix::io::RawFd;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{
+    recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags,
+    SockFlag, SockType,
+};
+use nix::sys::stat::Mode;
+use nix::sys::uio::IoVec;
+use nix::unistd::{close, mkfifo};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+// Bumped when the messages change, both ends of a socket must run the same version
+pub const PROTOCOL_VERSION: u32 = 3;
+// Size of the length prefix of each message
+const HEADER_SIZE: usize = 4;
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// The messages exchanged by the parent and the child during the setup of the container,
+// and by the parent and the spawner creating the child.
+// The socket of the child is closed when it executes the command, the parent reads
+// the end of file as the command being started.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    // The child asks for its IDs to be mapped, if it has its own user namespace
+    UidMapRequest {
+        has_userns: bool,
+    },
+    // The parent mapped the IDs, the child can go on
+    UidMapDone,
+    // A file descriptor is attached to the message
+    FdTransfer,
+    // The setup is finished, the child waits for Start
+    Ready,
+    // The container is started, the child loads its seccomp filter and executes the command
+    Start,
+    // A step of the setup failed in the child
+    // The errno is the one of the syscall which failed, if the step failed on one
+    SetupError {
+        stage: String,
+        errcode: Errcode,
+        errno: Option<i32>,
+        message: String,
+    },
+    // The parent asks the spawner for a new child, the socket of the child is attached.
+    // The directory of its cgroup follows in another message when it has one.
+    Spawn {
+        cgroup: bool,
+    },
+    // The child is created, its pidfd is attached when the kernel has pidfds.
+    // The intermediate process which created it exits, the parent reaps it.
+    Spawned {
+        pid: i32,
+        intermediate: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    message: Message,
+}
 
 pub fn generate_socket_pair() -> Result<(RawFd, RawFd), Errcode> {
     match socketpair(
@@ -17,21 +83,455 @@ pub fn generate_socket_pair() -> Result<(RawFd, RawFd), Errcode> {
     }
 }
 
-pub fn send_boolean(fd: RawFd, boolean: bool) -> Result<(), Errcode> {
-    let data: [u8; 1] = [boolean.into()];
-    if let Err(e) = send(fd, &data, MsgFlags::empty()) {
-        log::error!("Cannot send boolean through socket: {:?}", e);
-        return Err(Errcode::SocketError(1));
+pub fn send_message(fd: RawFd, message: Message) -> Result<(), Errcode> {
+    send_frame(fd, message, None)
+}
+
+// A file descriptor can be shared with another process by sending it as an
+// SCM_RIGHTS ancillary message, the receiver gets a new fd pointing to the same file
+pub fn send_fd(fd: RawFd, shared: RawFd) -> Result<(), Errcode> {
+    send_frame(fd, Message::FdTransfer, Some(shared))
+}
+
+// A message with a file descriptor attached, when there is one
+pub fn send_message_with_fd(
+    fd: RawFd,
+    message: Message,
+    shared: Option<RawFd>,
+) -> Result<(), Errcode> {
+    send_frame(fd, message, shared)
+}
+
+// Receives the next message, None when the other end closed the socket.
+// A setup failure of the child is returned as its error.
+pub fn recv_message(fd: RawFd) -> Result<Option<Message>, Errcode> {
+    match recv_frame(fd)? {
+        Some((message, Some(received))) => {
+            log::error!("Unexpected file descriptor received with {:?}", message);
+            if let Err(e) = close(received) {
+                log::error!("Unable to close received file descriptor: {:?}", e);
+            }
+            Err(Errcode::SocketError(8))
+        }
+        Some((message, None)) => Ok(Some(child_error(message)?)),
+        None => Ok(None),
+    }
+}
+
+// Like recv_message, with the file descriptor attached to the message if any
+pub fn recv_message_with_fd(fd: RawFd) -> Result<Option<(Message, Option<RawFd>)>, Errcode> {
+    match recv_frame(fd)? {
+        Some((message, received)) => match child_error(message) {
+            Ok(message) => Ok(Some((message, received))),
+            Err(e) => {
+                if let Some(received) = received {
+                    if let Err(e) = close(received) {
+                        log::error!("Unable to close received file descriptor: {:?}", e);
+                    }
+                }
+                Err(e)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn recv_fd(fd: RawFd) -> Result<RawFd, Errcode> {
+    match recv_frame(fd)? {
+        Some((Message::FdTransfer, Some(received))) => Ok(received),
+        Some((message, _)) => {
+            let message = child_error(message)?;
+            log::error!("Expected a file descriptor, received {:?}", message);
+            Err(Errcode::SocketError(6))
+        }
+        None => {
+            log::error!("Socket closed while waiting for a file descriptor");
+            Err(Errcode::SocketError(6))
+        }
+    }
+}
+
+// A created container waits for a process opening its exec fifo, which is how
+// another crabcan command reaches the parent holding it
+pub fn create_exec_fifo(path: &Path) -> Result<(), Errcode> {
+    if let Err(e) = mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR) {
+        log::error!("Cannot create exec fifo {}: {:?}", path.display(), e);
+        return Err(Errcode::SocketError(10));
+    }
+    Ok(())
+}
+
+// Opened before the container is reported as created, so crabcan start finds a reader as
+// soon as it can run. Without blocking, the open doesn't wait for crabcan start.
+pub fn open_exec_fifo(path: &Path) -> Result<File, Errcode> {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(fifo) => Ok(fifo),
+        Err(e) => {
+            log::error!("Cannot open exec fifo {}: {}", path.display(), e);
+            Err(Errcode::SocketError(11))
+        }
+    }
+}
+
+// Blocks until the container is started, the fifo is removed so it can't be started twice.
+// A read would return the end of file until a writer opens the fifo, poll waits for its byte.
+pub fn wait_exec_fifo(mut fifo: File, path: &Path) -> Result<(), Errcode> {
+    let polled = loop {
+        let mut fds = [PollFd::new(fifo.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, -1) {
+            Err(Errno::EINTR) => continue,
+            res => break res.map(drop).map_err(io::Error::from),
+        }
+    };
+    let mut byte = [0u8; 1];
+    let result = polled.and_then(|_| fifo.read_exact(&mut byte));
+    if let Err(e) = remove_file(path) {
+        log::error!("Cannot remove exec fifo {}: {}", path.display(), e);
+    }
+    if let Err(e) = result {
+        log::error!("Cannot read exec fifo {}: {}", path.display(), e);
+        return Err(Errcode::SocketError(11));
+    }
+    Ok(())
+}
+
+// Opening the fifo without blocking fails if no parent is waiting on it anymore
+pub fn release_exec_fifo(path: &Path) -> Result<(), Errcode> {
+    let fifo = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path);
+    let result = fifo.and_then(|mut fifo| fifo.write_all(&[0]));
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::ENXIO) => {
+            log::error!("No container is waiting on {}", path.display());
+            Err(Errcode::SocketError(12))
+        }
+        Err(e) => {
+            log::error!("Cannot write exec fifo {}: {}", path.display(), e);
+            Err(Errcode::SocketError(12))
+        }
+    }
+}
+
+// Logs a message the receiver didn't expect at this point of the setup
+pub fn unexpected(message: Option<Message>) -> Errcode {
+    match message {
+        Some(message) => log::error!("Unexpected message {:?}", message),
+        None => log::error!("Socket closed by the other process"),
+    }
+    Errcode::SocketError(8)
+}
+
+fn child_error(message: Message) -> Result<Message, Errcode> {
+    match message {
+        Message::SetupError {
+            stage,
+            errcode,
+            errno,
+            message,
+        } => {
+            log::error!(
+                "Container setup failed at {}: {} ({})",
+                stage,
+                errcode,
+                message
+            );
+            if let Some(errno) = errno {
+                log::debug!("Errno of the failure: {}", errno);
+            }
+            Err(errcode)
+        }
+        message => Ok(message),
+    }
+}
+
+// Each message is the JSON encoding of an envelope, prefixed by its length (big endian)
+fn send_frame(fd: RawFd, message: Message, shared: Option<RawFd>) -> Result<(), Errcode> {
+    let envelope = Envelope {
+        version: PROTOCOL_VERSION,
+        message,
+    };
+    let payload = match serde_json::to_vec(&envelope) {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("Cannot encode message {:?}: {}", envelope.message, e);
+            return Err(Errcode::SocketError(7));
+        }
+    };
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend(payload);
+
+    let iov = [IoVec::from_slice(&frame)];
+    let fds: Vec<RawFd> = shared.into_iter().collect();
+    let cmsg: Vec<ControlMessage> = match shared {
+        Some(_) => vec![ControlMessage::ScmRights(&fds)],
+        None => Vec::new(),
+    };
+    if let Err(e) = sendmsg(fd, &iov, &cmsg, MsgFlags::empty(), None) {
+        log::error!("Cannot send message {:?}: {:?}", envelope.message, e);
+        return Err(os_error(Errcode::SocketError(1), e));
     };
     Ok(())
 }
 
-pub fn recv_boolean(fd: RawFd) -> Result<bool, Errcode> {
-    let mut data: [u8; 1] = [0];
-    //the revieve method writes to the data mutable vector
-    if let Err(e) = recv(fd, &mut data, MsgFlags::empty()) {
-        log::error!("Cannot receive boolean from socket: {:?}", e);
-        return Err(Errcode::SocketError(2));
+// The message as it was sent, a setup failure isn't returned as an error
+pub fn recv_frame(fd: RawFd) -> Result<Option<(Message, Option<RawFd>)>, Errcode> {
+    let mut frame = vec![0u8; HEADER_SIZE + MAX_MESSAGE_SIZE];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+    let (size, received) = loop {
+        let iov = [IoVec::from_mut_slice(&mut frame)];
+        match recvmsg(fd, &iov, Some(&mut cmsg_buffer), MsgFlags::MSG_CMSG_CLOEXEC) {
+            Ok(msg) => {
+                let received = msg.cmsgs().find_map(|cmsg| match cmsg {
+                    ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+                    _ => None,
+                });
+                break (msg.bytes, received);
+            }
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                log::error!("Cannot receive message from socket: {:?}", e);
+                return Err(os_error(Errcode::SocketError(2), e));
+            }
+        }
+    };
+    if size == 0 {
+        return Ok(None);
+    }
+
+    let envelope = decode(&frame[..size]);
+    match (envelope, received) {
+        (Ok(envelope), received) => Ok(Some((envelope.message, received))),
+        (Err(e), Some(received)) => {
+            if let Err(e) = close(received) {
+                log::error!("Unable to close received file descriptor: {:?}", e);
+            }
+            Err(e)
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+fn decode(frame: &[u8]) -> Result<Envelope, Errcode> {
+    let length = match frame.get(..HEADER_SIZE) {
+        Some(header) => u32::from_be_bytes(header.try_into().unwrap()) as usize,
+        None => {
+            log::error!("Truncated message of {} bytes", frame.len());
+            return Err(Errcode::SocketError(7));
+        }
+    };
+    let payload = &frame[HEADER_SIZE..];
+    if payload.len() != length {
+        log::error!(
+            "Message length {} doesn't match its header {}",
+            payload.len(),
+            length
+        );
+        return Err(Errcode::SocketError(7));
+    }
+    // The version is checked first, a message of another version may not parse
+    let version = serde_json::from_slice::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|v| v.get("version").and_then(|v| v.as_u64()));
+    if version != Some(PROTOCOL_VERSION as u64) {
+        log::error!(
+            "Protocol version {:?} of the other process, expected {}",
+            version,
+            PROTOCOL_VERSION
+        );
+        return Err(Errcode::SocketError(9));
+    }
+    match serde_json::from_slice(payload) {
+        Ok(envelope) => Ok(envelope),
+        Err(e) => {
+            log::error!("Cannot decode message: {}", e);
+            Err(Errcode::SocketError(7))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{pipe, read, write};
+
+    // Sends the raw bytes as a single packet, as the other end would with send_frame
+    fn send_raw(fd: RawFd, frame: &[u8]) {
+        assert_eq!(write(fd, frame).unwrap(), frame.len());
+    }
+
+    fn frame(payload: &str) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend(payload.as_bytes());
+        frame
+    }
+
+    fn close_pair((a, b): (RawFd, RawFd)) {
+        close(a).unwrap();
+        close(b).unwrap();
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let sockets = generate_socket_pair().unwrap();
+        send_message(sockets.0, Message::UidMapRequest { has_userns: true }).unwrap();
+        send_message(
+            sockets.0,
+            Message::Spawned {
+                pid: 12,
+                intermediate: 11,
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            recv_message(sockets.1),
+            Ok(Some(Message::UidMapRequest { has_userns: true }))
+        ));
+        assert!(matches!(
+            recv_message(sockets.1),
+            Ok(Some(Message::Spawned {
+                pid: 12,
+                intermediate: 11
+            }))
+        ));
+        close(sockets.0).unwrap();
+        assert!(matches!(recv_message(sockets.1), Ok(None)));
+        close(sockets.1).unwrap();
+    }
+
+    #[test]
+    fn fds_round_trip() {
+        let sockets = generate_socket_pair().unwrap();
+        let (reader, writer) = pipe().unwrap();
+        send_fd(sockets.0, writer).unwrap();
+        close(writer).unwrap();
+        let received = recv_fd(sockets.1).unwrap();
+        write(received, b"ok").unwrap();
+        close(received).unwrap();
+        let mut buffer = [0u8; 2];
+        assert_eq!(read(reader, &mut buffer).unwrap(), 2);
+        assert_eq!(&buffer, b"ok");
+        close(reader).unwrap();
+
+        // A fd where none is expected is closed and refused
+        let (reader, writer) = pipe().unwrap();
+        send_message_with_fd(sockets.0, Message::Ready, Some(writer)).unwrap();
+        close(writer).unwrap();
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(8))
+        ));
+        // The received copy of the writer was closed, the pipe has no writer left
+        assert_eq!(read(reader, &mut buffer).unwrap(), 0);
+        close(reader).unwrap();
+        close_pair(sockets);
+    }
+
+    #[test]
+    fn setup_errors_are_returned_as_errors() {
+        let sockets = generate_socket_pair().unwrap();
+        let failure = || Message::SetupError {
+            stage: "exec".to_string(),
+            errcode: Errcode::ChildProcessError(3),
+            errno: Some(libc::ENOENT),
+            message: "ENOENT".to_string(),
+        };
+        send_message(sockets.0, failure()).unwrap();
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::ChildProcessError(3))
+        ));
+        // Relayed as they are by the spawner
+        send_message(sockets.0, failure()).unwrap();
+        assert!(matches!(
+            recv_frame(sockets.1),
+            Ok(Some((
+                Message::SetupError {
+                    errno: Some(libc::ENOENT),
+                    ..
+                },
+                None
+            )))
+        ));
+        close_pair(sockets);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let sockets = generate_socket_pair().unwrap();
+        let other = PROTOCOL_VERSION + 1;
+        let payload = format!(r#"{{"version":{},"message":{{"type":"ready"}}}}"#, other);
+        send_raw(sockets.0, &frame(&payload));
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(9))
+        ));
+        // Checked before the message, which may not parse in another version
+        let payload = r#"{"version":1,"message":{"type":"unknown"}}"#;
+        send_raw(sockets.0, &frame(payload));
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(9))
+        ));
+        send_raw(sockets.0, &frame(r#"{"message":{"type":"ready"}}"#));
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(9))
+        ));
+        // The current version goes through
+        let payload = format!(
+            r#"{{"version":{},"message":{{"type":"ready"}}}}"#,
+            PROTOCOL_VERSION
+        );
+        send_raw(sockets.0, &frame(&payload));
+        assert!(matches!(recv_message(sockets.1), Ok(Some(Message::Ready))));
+        close_pair(sockets);
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let sockets = generate_socket_pair().unwrap();
+        let payload = format!(
+            r#"{{"version":{},"message":{{"type":"ready"}}}}"#,
+            PROTOCOL_VERSION
+        );
+        // Truncated length prefix
+        send_raw(sockets.0, &[0, 0]);
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(7))
+        ));
+        // Length prefix longer or shorter than the payload
+        let mut long = frame(&payload);
+        long[3] += 1;
+        send_raw(sockets.0, &long);
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(7))
+        ));
+        let mut truncated = frame(&payload);
+        truncated.pop();
+        send_raw(sockets.0, &truncated);
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(7))
+        ));
+        // Unknown message of the current version
+        let payload = format!(
+            r#"{{"version":{},"message":{{"type":"unknown"}}}}"#,
+            PROTOCOL_VERSION
+        );
+        send_raw(sockets.0, &frame(&payload));
+        assert!(matches!(
+            recv_message(sockets.1),
+            Err(Errcode::SocketError(7))
+        ));
+        close_pair(sockets);
     }
-    Ok(data[0] == 1)
 }
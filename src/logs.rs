@@ -0,0 +1,387 @@
+use crate::attach::AttachServer;
+use crate::errors::{os_error, Errcode};
+use crate::state::{container_dir, ContainerState};
+use crate::tty::write_all;
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::unistd::{dup2, pipe2, read};
+use serde::{Deserialize, Serialize};
+use std::fs::{metadata, rename, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// Outputs of the command, one JSON entry per line, in the state directory of the container
+pub const CONTAINER_LOG: &str = "container.log";
+// Once the log reaches LOG_MAX_SIZE, it is renamed to container.log.1, the previous
+// container.log.1 to container.log.2 and so on, keeping LOG_MAX_FILES rotated files
+const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const LOG_MAX_FILES: usize = 3;
+// A longer line is split into several entries
+const LINE_MAX_SIZE: usize = 16 * 1024;
+const BUFFER_SIZE: usize = 4096;
+// Interval of the checks of crabcan logs --follow for new entries
+const FOLLOW_POLL_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    stream: Stream,
+    // RFC 3339, with nanoseconds
+    time: String,
+    log: String,
+}
+
+// Without a terminal, the outputs of the command go through crabcan in pipes:
+// the container process gets the write ends, crabcan reads the other ends.
+// A detached container also gets its input from crabcan, written by attached clients.
+#[derive(Debug, Clone, Copy)]
+pub struct StdioPipes {
+    pub stdin: Option<(RawFd, RawFd)>,
+    pub stdout: (RawFd, RawFd),
+    pub stderr: (RawFd, RawFd),
+}
+
+impl StdioPipes {
+    // The ends kept by crabcan
+    pub fn parent_ends(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.stdout.0, self.stderr.0];
+        fds.extend(self.stdin.map(|p| p.1));
+        fds
+    }
+
+    // The ends becoming the standard input and outputs of the command
+    pub fn child_ends(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.stdout.1, self.stderr.1];
+        fds.extend(self.stdin.map(|p| p.0));
+        fds
+    }
+}
+
+pub fn open_stdio_pipes(input: bool) -> Result<StdioPipes, Errcode> {
+    let mut pipes = Vec::new();
+    for _ in 0..(2 + input as usize) {
+        // The command only keeps the copies made as its input and outputs
+        match pipe2(OFlag::O_CLOEXEC) {
+            Ok(pipe) => pipes.push(pipe),
+            Err(e) => {
+                log::error!("Cannot create the pipes of the outputs: {:?}", e);
+                return Err(Errcode::LogsError(0));
+            }
+        }
+    }
+    Ok(StdioPipes {
+        stdin: pipes.get(2).copied(),
+        stdout: pipes[0],
+        stderr: pipes[1],
+    })
+}
+
+// Runs in the container process, right before executing the command
+pub fn redirect_stdio(pipes: &StdioPipes) -> Result<(), Errcode> {
+    let mut redirects = vec![
+        (pipes.stdout.1, libc::STDOUT_FILENO),
+        (pipes.stderr.1, libc::STDERR_FILENO),
+    ];
+    redirects.extend(pipes.stdin.map(|p| (p.0, libc::STDIN_FILENO)));
+    for (pipe, fd) in redirects {
+        if let Err(e) = dup2(pipe, fd) {
+            log::error!("Cannot redirect fd {} to crabcan: {:?}", fd, e);
+            return Err(os_error(Errcode::LogsError(1), e));
+        }
+    }
+    Ok(())
+}
+
+// The log of a container, shared by the threads capturing its outputs
+#[derive(Debug, Clone)]
+pub struct ContainerLog {
+    file: Arc<Mutex<LogFile>>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    // Closed after an error, the outputs are then only copied
+    file: Option<File>,
+    size: u64,
+}
+
+impl ContainerLog {
+    pub fn open(id: &str) -> Result<ContainerLog, Errcode> {
+        let path = container_dir(id).join(CONTAINER_LOG);
+        let file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("Cannot open {}: {}", path.display(), e);
+                return Err(Errcode::LogsError(2));
+            }
+        };
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(ContainerLog {
+            file: Arc::new(Mutex::new(LogFile {
+                path,
+                file: Some(file),
+                size,
+            })),
+        })
+    }
+
+    fn write(&self, stream: Stream, line: &[u8]) {
+        let entry = LogEntry {
+            stream,
+            time: humantime::format_rfc3339_nanos(SystemTime::now()).to_string(),
+            log: String::from_utf8_lossy(line).into_owned(),
+        };
+        let mut entry = match serde_json::to_vec(&entry) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("Cannot serialize a log entry: {}", e);
+                return;
+            }
+        };
+        entry.push(b'\n');
+        // A thread panicking while holding the lock can't leave the file half updated
+        let mut log = match self.file.lock() {
+            Ok(log) => log,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = log.write(&entry) {
+            log::error!(
+                "Cannot write to {}, output isn't logged anymore: {}",
+                log.path.display(),
+                e
+            );
+            log.file = None;
+        }
+    }
+}
+
+impl LogFile {
+    fn write(&mut self, entry: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        if self.size > 0 && self.size + entry.len() as u64 > LOG_MAX_SIZE {
+            self.rotate()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(entry)?;
+            self.size += entry.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..LOG_MAX_FILES).rev() {
+            match rename(rotated_log(&self.path, n), rotated_log(&self.path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        rename(&self.path, rotated_log(&self.path, 1))?;
+        self.file = Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+        );
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_log(path: &Path, n: usize) -> PathBuf {
+    path.with_file_name(format!("{}.{}", CONTAINER_LOG, n))
+}
+
+// Copies an output of the container to crabcan and to the attached clients, and logs it
+// line by line. The thread ends once no process of the container has the output open anymore.
+pub fn capture(
+    from: RawFd,
+    to: RawFd,
+    stream: Stream,
+    log: ContainerLog,
+    attach: Option<AttachServer>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let mut line = Vec::new();
+        // A failing copy, like a closed output, doesn't stop the logging
+        let mut copied = true;
+        loop {
+            let size = match read(from, &mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(Errno::EINTR) => continue,
+                // EIO on a terminal without slave
+                Err(_) => break,
+            };
+            copied = copied && write_all(to, &buffer[..size]);
+            if let Some(attach) = &attach {
+                attach.broadcast(stream, &buffer[..size]);
+            }
+            for chunk in buffer[..size].split_inclusive(|c| *c == b'\n') {
+                line.extend_from_slice(chunk);
+                if line.ends_with(b"\n") || line.len() >= LINE_MAX_SIZE {
+                    log.write(stream, &line);
+                    line.clear();
+                }
+            }
+        }
+        if !line.is_empty() {
+            log.write(stream, &line);
+        }
+    })
+}
+
+// The time given to crabcan logs --since, as a timestamp or a duration before now
+pub fn parse_since(since: &str) -> Result<SystemTime, Errcode> {
+    if let Ok(time) = humantime::parse_rfc3339_weak(since) {
+        return Ok(time);
+    }
+    match humantime::parse_duration(since) {
+        Ok(duration) => SystemTime::now()
+            .checked_sub(duration)
+            .ok_or(Errcode::ArgumentInvalid("since")),
+        Err(_) => Err(Errcode::ArgumentInvalid("since")),
+    }
+}
+
+// Prints the outputs of a container, the rotated logs first. With follow, new entries are
+// printed until the container is stopped.
+pub fn print_logs(
+    id: &str,
+    follow: bool,
+    tail: Option<usize>,
+    since: Option<SystemTime>,
+) -> Result<(), Errcode> {
+    ContainerState::load(id)?;
+    let path = container_dir(id).join(CONTAINER_LOG);
+    let mut entries = Vec::new();
+    for n in (1..=LOG_MAX_FILES).rev() {
+        if let Ok(mut file) = File::open(rotated_log(&path, n)) {
+            entries.extend(LogReader::read_file(&mut file, &mut Vec::new()));
+        }
+    }
+    let mut reader = LogReader {
+        path,
+        file: None,
+        pending: Vec::new(),
+    };
+    entries.extend(reader.read_entries());
+    entries.retain(|e| is_since(e, since));
+    if let Some(tail) = tail {
+        entries.drain(..entries.len().saturating_sub(tail));
+    }
+    if !print_entries(&entries)? || !follow {
+        return Ok(());
+    }
+
+    loop {
+        // Checked first, so the entries logged until the container stopped are printed
+        let active = ContainerState::load(id)?.is_active();
+        let mut entries = reader.read_entries();
+        entries.retain(|e| is_since(e, since));
+        if !print_entries(&entries)? || !active {
+            return Ok(());
+        }
+        sleep(FOLLOW_POLL_DELAY);
+    }
+}
+
+fn is_since(entry: &LogEntry, since: Option<SystemTime>) -> bool {
+    match (since, humantime::parse_rfc3339(&entry.time)) {
+        (Some(since), Ok(time)) => time >= since,
+        _ => true,
+    }
+}
+
+// Returns false once the output of crabcan is closed, like when piped to head
+fn print_entries(entries: &[LogEntry]) -> Result<bool, Errcode> {
+    let stdout = io::stdout();
+    let stderr = io::stderr();
+    let (mut stdout, mut stderr) = (stdout.lock(), stderr.lock());
+    for entry in entries.iter() {
+        let res = match entry.stream {
+            Stream::Stdout => stdout.write_all(entry.log.as_bytes()),
+            Stream::Stderr => stderr.write_all(entry.log.as_bytes()),
+        };
+        match res {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(false),
+            Err(e) => {
+                log::error!("Cannot print the logs: {}", e);
+                return Err(Errcode::LogsError(3));
+            }
+        }
+    }
+    let _ = stdout.flush();
+    Ok(true)
+}
+
+// Reads the current log as it grows, following it when it is rotated
+struct LogReader {
+    path: PathBuf,
+    file: Option<File>,
+    // The end of an entry still being written
+    pending: Vec<u8>,
+}
+
+impl LogReader {
+    fn read_entries(&mut self) -> Vec<LogEntry> {
+        let mut entries = match &mut self.file {
+            Some(file) => LogReader::read_file(file, &mut self.pending),
+            None => Vec::new(),
+        };
+        // The rest of a rotated log was read above, the new log is read from its start
+        let current = metadata(&self.path).map(|m| m.ino()).ok();
+        let opened = self
+            .file
+            .as_ref()
+            .and_then(|f| f.metadata().ok())
+            .map(|m| m.ino());
+        if current.is_some() && current != opened {
+            if let Ok(mut file) = File::open(&self.path) {
+                self.pending.clear();
+                entries.extend(LogReader::read_file(&mut file, &mut self.pending));
+                self.file = Some(file);
+            }
+        }
+        entries
+    }
+
+    // Only complete lines are parsed, the rest is kept in pending
+    fn read_file(file: &mut File, pending: &mut Vec<u8>) -> Vec<LogEntry> {
+        if let Err(e) = file.read_to_end(pending) {
+            log::error!("Cannot read the log of the container: {}", e);
+        }
+        let end = match pending.iter().rposition(|c| *c == b'\n') {
+            Some(end) => end + 1,
+            None => return Vec::new(),
+        };
+        let lines: Vec<u8> = pending.drain(..end).collect();
+        lines
+            .split(|c| *c == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("Invalid line in the log of the container: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
@@ -2,24 +2,67 @@ use std::process::exit;
 
 #[macro_use]
 extern crate scan_fmt;
+mod attach;
 mod capabilities;
 mod child;
 mod cli;
 mod config;
 mod container;
+mod daemon;
 mod errors;
+mod exec;
 mod hostname;
+mod init;
 mod ipc;
+mod logs;
 mod mounts;
 mod namespaces;
+mod ps;
 mod resources;
+mod restart;
+mod shim;
+mod signals;
+mod spawner;
+mod state;
+mod supervisor;
 mod syscalls;
+mod tty;
+mod user;
 
 fn main() {
     match cli::parse_args() {
         Ok(args) => {
             log::info!("{:?}", args);
-            errors::exit_with_retcode(container::start(args))
+            match args.cmd {
+                cli::Command::Run(run) => errors::exit_with_retcode(container::run(run, false)),
+                cli::Command::Create(run) => errors::exit_with_retcode(container::run(run, true)),
+                cli::Command::Start { id } => errors::exit_with_retcode(container::start(&id)),
+                cli::Command::Stop { id, time, signal } => {
+                    errors::exit_with_retcode(container::stop(&id, time, signal))
+                }
+                cli::Command::State { id } => errors::exit_with_retcode(container::state(&id)),
+                cli::Command::Exec(exec) => errors::exit_with_retcode(exec::exec(exec)),
+                cli::Command::Logs {
+                    id,
+                    follow,
+                    tail,
+                    since,
+                } => errors::exit_with_retcode(logs::print_logs(&id, follow, tail, since)),
+                cli::Command::Attach { id, detach_keys } => {
+                    errors::exit_with_retcode(attach::attach(&id, detach_keys))
+                }
+                cli::Command::Kill { id, signal } => {
+                    errors::exit_with_retcode(shim::kill(&id, signal))
+                }
+                cli::Command::Stats { id } => errors::exit_with_retcode(shim::stats(&id)),
+                cli::Command::Shim {
+                    hold,
+                    notify_fd,
+                    run,
+                } => errors::exit_with_retcode(shim::run_shim(run, hold, notify_fd)),
+                cli::Command::Ps { id, json } => errors::exit_with_retcode(ps::ps(&id, json)),
+                cli::Command::Inspect { id } => errors::exit_with_retcode(container::inspect(&id)),
+            }
         }
         Err(e) => {
             log::error!("Error while parsing arguments:\n\t{}", e);
@@ -1,4 +1,4 @@
-use crate::errors::Errcode;
+use crate::errors::{io_error, os_error, Errcode};
 use std::path::PathBuf;
 
 use nix::mount::{mount, MsFlags};
@@ -14,7 +14,7 @@ pub fn unmount_path(path: &PathBuf) -> Result<(), Errcode> {
         Ok(_) => Ok(()),
         Err(e) => {
             log::error!("Unable to umount {}: {}", path.to_str().unwrap(), e);
-            Err(Errcode::MountsError(0))
+            Err(os_error(Errcode::MountsError(0), e))
         }
     }
 }
@@ -28,7 +28,7 @@ pub fn delete_dir(path: &PathBuf) -> Result<(), Errcode> {
                 path.to_str().unwrap(),
                 e
             );
-            Err(Errcode::MountsError(1))
+            Err(io_error(Errcode::MountsError(1), &e))
         }
     }
 }
@@ -59,16 +59,16 @@ pub fn setmountpoint(mount_dir: &PathBuf) -> Result<(), Errcode> {
     let old_root_tail = format!("oldroot.{}", random_string(6));
     let put_old = new_root.join(PathBuf::from(old_root_tail.clone()));
     create_directory(&put_old)?;
-    if let Err(_) = pivot_root(&new_root, &put_old) {
-        return Err(Errcode::MountsError(4));
+    if let Err(e) = pivot_root(&new_root, &put_old) {
+        return Err(os_error(Errcode::MountsError(4), e));
     }
 
     log::debug!("Unmounting old root");
     let old_root = PathBuf::from(format!("/{}", old_root_tail));
 
     // Ensure we are not inside the directory we want to umount
-    if let Err(_) = chdir(&PathBuf::from("/")) {
-        return Err(Errcode::MountsError(5));
+    if let Err(e) = chdir(&PathBuf::from("/")) {
+        return Err(os_error(Errcode::MountsError(5), e));
     }
     unmount_path(&old_root)?;
     delete_dir(&old_root)?;
@@ -84,7 +84,7 @@ pub fn create_directory(path: &PathBuf) -> Result<(), Errcode> {
         Ok(_) => Ok(()),
         Err(e) => {
             log::error!("Cannot create directory {}: {}", path.to_str().unwrap(), e);
-            Err(Errcode::MountsError(2))
+            Err(io_error(Errcode::MountsError(2), &e))
         }
     }
 }
@@ -128,7 +128,7 @@ pub fn mount_directory(
             } else {
                 log::error!("Cannot remount {}: {}", mount_point.to_str().unwrap(), e);
             }
-            Err(Errcode::MountsError(3))
+            Err(os_error(Errcode::MountsError(3), e))
         }
     }
 }
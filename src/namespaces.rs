@@ -1,13 +1,26 @@
-use nix::sched::{unshare, CloneFlags};
-use nix::unistd::Pid;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::sys::stat::{stat, Mode};
+use nix::unistd::{close, Pid};
+use nix::unistd::{getgid, getuid, Gid, Uid};
 use nix::unistd::{setgroups, setresgid, setresuid};
-use nix::unistd::{Gid, Uid};
-use std::fs::File;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs::{create_dir_all, read_to_string, remove_file, write, File};
 use std::io::Write;
 use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::str::FromStr;
 
-use crate::errors::Errcode;
-use crate::ipc::{recv_boolean, send_boolean};
+use crate::errors::{io_error, os_error, Errcode};
+use crate::ipc::{recv_message, send_message, unexpected, Message};
+use crate::shim::{helper_waited, spawn_helper};
+use crate::state::ContainerState;
+use crate::user::ContainerUser;
 
 // The general user namespace configuration is the following:
 
@@ -19,17 +32,19 @@ use crate::ipc::{recv_boolean, send_boolean};
 // Parent process tells child process to continue
 // Then child process switches his UID / GID to the one provided by the user as a parameter.
 
-pub fn userns(fd: RawFd, uid: u32) -> Result<(), Errcode> {
-    log::debug!("Setting up user namespace with UID {}", uid);
+// In rootless mode, the user namespace is created by clone, the child only waits for its mappings.
+pub fn userns(fd: RawFd, rootless: bool) -> Result<(), Errcode> {
+    log::debug!("Setting up user namespace");
 
-    let has_userns = match unshare(CloneFlags::CLONE_NEWUSER) {
-        Ok(_) => true,
-        Err(_) => false,
-    };
-    send_boolean(fd, has_userns)?;
+    let has_userns = rootless || unshare(CloneFlags::CLONE_NEWUSER).is_ok();
+    send_message(fd, Message::UidMapRequest { has_userns })?;
 
-    if recv_boolean(fd)? {
-        return Err(Errcode::NamespacesError(0));
+    match recv_message(fd)? {
+        Some(Message::UidMapDone) => {}
+        message => {
+            unexpected(message);
+            return Err(Errcode::NamespacesError(0));
+        }
     }
 
     if has_userns {
@@ -37,29 +52,346 @@ pub fn userns(fd: RawFd, uid: u32) -> Result<(), Errcode> {
     } else {
         log::info!("User namespaces not supported, continuing...");
     }
+    Ok(())
+}
 
-    log::debug!("Switching to uid {} / gid {}...", uid, uid);
-    let gid = Gid::from_raw(uid);
-    let uid = Uid::from_raw(uid);
+pub fn switch_user(user: &ContainerUser, rootless: bool) -> Result<(), Errcode> {
+    log::debug!(
+        "Switching to uid {} / gid {} / groups {:?}...",
+        user.uid,
+        user.gid,
+        user.groups
+    );
+    let gid = Gid::from_raw(user.gid);
+    let uid = Uid::from_raw(user.uid);
+    let mut groups = vec![gid];
+    groups.extend(user.groups.iter().map(|g| Gid::from_raw(*g)));
 
-    if let Err(_) = setgroups(&[gid]) {
-        return Err(Errcode::NamespacesError(1));
+    // Once setgroups is denied to map the GID without newgidmap, the groups can't be changed
+    match setgroups(&groups) {
+        Ok(_) => {}
+        Err(Errno::EPERM) if rootless => {
+            if !user.groups.is_empty() {
+                log::warn!("Supplementary groups can't be set without newgidmap");
+            }
+        }
+        Err(e) => return Err(os_error(Errcode::NamespacesError(1), e)),
     }
 
     //We use the setresuid and setresgid to set the UID and GID (respectively) of the process.
     // This will set the real user ID
-    if let Err(_) = setresgid(gid, gid, gid) {
-        return Err(Errcode::NamespacesError(2));
+    if let Err(e) = setresgid(gid, gid, gid) {
+        return Err(os_error(Errcode::NamespacesError(2), e));
     }
 
-    if let Err(_) = setresuid(uid, uid, uid) {
-        return Err(Errcode::NamespacesError(3));
+    if let Err(e) = setresuid(uid, uid, uid) {
+        return Err(os_error(Errcode::NamespacesError(3), e));
     }
     Ok(())
 }
 
-const USERNS_OFFSET: u64 = 10000;
-const USERNS_COUNT: u64 = 2000;
+// Without a subordinate range for the user, the IDs of the container start at USERNS_OFFSET
+const USERNS_OFFSET: u32 = 10000;
+// Number of IDs allocated to each container
+const USERNS_COUNT: u32 = 2000;
+// Blocks of the default pool, as many containers can run at the same time
+const USERNS_DEFAULT_BLOCKS: u32 = 1000;
+
+pub const SUBUID_FILE: &str = "/etc/subuid";
+pub const SUBGID_FILE: &str = "/etc/subgid";
+
+// Setuid helpers from shadow-utils, mapping the subordinate IDs of an unprivileged user
+pub const NEWUIDMAP: &str = "newuidmap";
+pub const NEWGIDMAP: &str = "newgidmap";
+
+// A range of count IDs starting at inside in the namespace, and at outside on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMap {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdMap {
+    pub fn contains_inside(&self, id: u32) -> bool {
+        id >= self.inside && (id - self.inside) < self.count
+    }
+
+    // The ID inside the container of a host ID of the range
+    pub fn inside_id(&self, outside: u32) -> Option<u32> {
+        match outside >= self.outside && (outside - self.outside) < self.count {
+            true => Some(self.inside + (outside - self.outside)),
+            false => None,
+        }
+    }
+
+    // Tells if both ranges share host IDs
+    pub fn overlaps(&self, other: &IdMap) -> bool {
+        let end = self.outside as u64 + self.count as u64;
+        let other_end = other.outside as u64 + other.count as u64;
+        (self.outside as u64) < other_end && (other.outside as u64) < end
+    }
+}
+
+// Parses inside:outside:count, as given on the command line
+impl FromStr for IdMap {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<IdMap, Errcode> {
+        let fields: Vec<&str> = s.split(':').collect();
+        if let [inside, outside, count] = fields[..] {
+            if let (Ok(inside), Ok(outside), Ok(count)) =
+                (inside.parse(), outside.parse(), count.parse())
+            {
+                return Ok(IdMap {
+                    inside,
+                    outside,
+                    count,
+                });
+            }
+        }
+        Err(Errcode::ArgumentInvalid("idmap"))
+    }
+}
+
+// The format of a line of /proc/<pid>/uid_map
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.inside, self.outside, self.count)
+    }
+}
+
+// Fails if one of the ranges uses host IDs already given to another container
+pub fn check_id_maps(maps: &[IdMap], taken: &[IdMap]) -> Result<(), Errcode> {
+    for map in maps.iter() {
+        if let Some(other) = taken.iter().find(|t| t.overlaps(map)) {
+            log::error!(
+                "ID range {} overlaps with range {} of a running container",
+                map,
+                other
+            );
+            return Err(Errcode::NamespacesError(8));
+        }
+    }
+    Ok(())
+}
+
+// Gives the container a block of USERNS_COUNT IDs from the subordinate ranges of the user
+// calling crabcan (see subuid(5)), skipping the ranges given to other containers
+pub fn allocate_id_maps(subid_file: &str, taken: &[IdMap]) -> Result<Vec<IdMap>, Errcode> {
+    let mut ranges = subordinate_ranges(subid_file);
+    let mut taken = taken.to_vec();
+    if ranges.is_empty() {
+        log::debug!("No range for the user in {}, using default", subid_file);
+        ranges.push((USERNS_OFFSET, USERNS_COUNT * USERNS_DEFAULT_BLOCKS));
+        // The IDs given to other users, for their rootless containers, are left to them
+        taken.extend(all_subordinate_ranges(subid_file));
+    }
+
+    match free_block(&ranges, &taken, 0) {
+        Some(map) => Ok(vec![map]),
+        None => {
+            log::error!("No free ID range left in {}", subid_file);
+            Err(Errcode::NamespacesError(9))
+        }
+    }
+}
+
+// Without privileges, root inside the container is the user calling crabcan, and the other IDs
+// come from its subordinate ranges. Without the helper or a free range, only this ID is mapped.
+pub fn rootless_id_maps(
+    subid_file: &str,
+    helper: &str,
+    own_id: u32,
+    taken: &[IdMap],
+) -> Vec<IdMap> {
+    let own = IdMap {
+        inside: 0,
+        outside: own_id,
+        count: 1,
+    };
+    if !helper_installed(helper) {
+        log::warn!(
+            "{} isn't installed, only ID {} is mapped inside the container",
+            helper,
+            own_id
+        );
+        return vec![own];
+    }
+    match free_block(&subordinate_ranges(subid_file), taken, 1) {
+        Some(block) => vec![own, block],
+        None => {
+            log::warn!(
+                "No free range in {}, only ID {} is mapped inside the container",
+                subid_file,
+                own_id
+            );
+            vec![own]
+        }
+    }
+}
+
+// First block of USERNS_COUNT IDs inside the ranges which isn't used by another container
+fn free_block(ranges: &[(u32, u32)], taken: &[IdMap], inside: u32) -> Option<IdMap> {
+    for (start, count) in ranges.iter() {
+        let end = *start as u64 + *count as u64;
+        let mut block = *start as u64;
+        while block + USERNS_COUNT as u64 <= end {
+            let map = IdMap {
+                inside,
+                outside: block as u32,
+                count: USERNS_COUNT,
+            };
+            if !taken.iter().any(|t| t.overlaps(&map)) {
+                return Some(map);
+            }
+            block += USERNS_COUNT as u64;
+        }
+    }
+    None
+}
+
+fn helper_installed(helper: &str) -> bool {
+    match env::var_os("PATH") {
+        Some(paths) => env::split_paths(&paths).any(|dir| dir.join(helper).is_file()),
+        None => false,
+    }
+}
+
+// Lines of /etc/subuid and /etc/subgid are name:start:count, the user can be given by name or ID.
+// With sudo, the ranges are the ones of the user who called sudo.
+fn subordinate_ranges(subid_file: &str) -> Vec<(u32, u32)> {
+    let uid = match env::var("SUDO_UID") {
+        Ok(uid) => uid,
+        Err(_) => getuid().to_string(),
+    };
+    let name = match env::var("SUDO_USER") {
+        Ok(name) => Some(name),
+        Err(_) => user_name(&uid),
+    };
+
+    let content = match read_to_string(subid_file) {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.trim().split(':').collect();
+            match fields[..] {
+                [user, start, count] if user == uid || Some(user) == name.as_deref() => {
+                    Some((start.parse().ok()?, count.parse().ok()?))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn all_subordinate_ranges(subid_file: &str) -> Vec<IdMap> {
+    let content = match read_to_string(subid_file) {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.trim().split(':').collect();
+            match fields[..] {
+                [_, start, count] => Some(IdMap {
+                    inside: 0,
+                    outside: start.parse().ok()?,
+                    count: count.parse().ok()?,
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn user_name(uid: &str) -> Option<String> {
+    let passwd = read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[..] {
+            [name, _, id, ..] if id == uid => Some(name.to_string()),
+            _ => None,
+        }
+    })
+}
+
+// All the ranges of a map are written at once, the kernel only accepts a single write
+fn write_id_map(pid: Pid, file: &str, maps: &[IdMap]) -> Result<(), Errcode> {
+    let content: Vec<String> = maps.iter().map(|m| m.to_string()).collect();
+    let path = format!("/proc/{}/{}", pid.as_raw(), file);
+    match File::create(&path) {
+        Ok(mut f) => {
+            if let Err(e) = f.write_all(content.join("\n").as_bytes()) {
+                log::error!("Cannot write {}: {}", path, e);
+                return Err(Errcode::NamespacesError(4));
+            }
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Cannot open {}: {}", path, e);
+            Err(Errcode::NamespacesError(5))
+        }
+    }
+}
+
+// The kernel lets an unprivileged process map its own ID, once setgroups is denied for the GID.
+// Any other mapping goes through the setuid helper, which checks it against /etc/subuid.
+fn write_id_map_rootless(
+    pid: Pid,
+    file: &str,
+    helper: &str,
+    maps: &[IdMap],
+    own_id: u32,
+) -> Result<(), Errcode> {
+    let own = IdMap {
+        inside: 0,
+        outside: own_id,
+        count: 1,
+    };
+    if maps == [own] {
+        if file == "gid_map" {
+            let path = format!("/proc/{}/setgroups", pid.as_raw());
+            if let Err(e) = std::fs::write(&path, "deny") {
+                log::error!("Cannot write {}: {}", path, e);
+                return Err(Errcode::NamespacesError(10));
+            }
+        }
+        return write_id_map(pid, file, maps);
+    }
+
+    let mut cmd = Command::new(helper);
+    cmd.arg(pid.as_raw().to_string());
+    for map in maps.iter() {
+        cmd.args(&[
+            map.inside.to_string(),
+            map.outside.to_string(),
+            map.count.to_string(),
+        ]);
+    }
+    // A detached crabcan reaps its orphans, it must leave the helper to this wait
+    let helper_pid = |helper: &Child| Some(Pid::from_raw(helper.id() as i32));
+    let status = spawn_helper(|| cmd.spawn(), helper_pid).and_then(|mut helper| {
+        let status = helper.wait();
+        helper_waited(Pid::from_raw(helper.id() as i32));
+        status
+    });
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => {
+            log::error!("{} failed with {}", helper, status);
+            Err(Errcode::NamespacesError(11))
+        }
+        Err(e) => {
+            log::error!("Cannot run {}: {}", helper, e);
+            Err(Errcode::NamespacesError(11))
+        }
+    }
+}
 
 // The file /proc/<pid>/uidmap is used by the Linux kernel to map the user IDs inside and outside the namespace of a process.
 // The format is the following:
@@ -69,31 +401,528 @@ const USERNS_COUNT: u64 = 2000;
 // inside the container will have a UID 1000 outside the container.
 // In the same way, a UID of 1 inside maps to a UID of 1001 outside, but a UID of 6
 // inside doesn’t map to 1006 outside as only 5 UID are allowed to be mapped.
-pub fn handle_child_uid_map(pid: Pid, fd: RawFd) -> Result<(), Errcode> {
-    if recv_boolean(fd)? {
-        if let Ok(mut uid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "uid_map")) {
-            if let Err(_) =
-                uid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes())
-            {
-                return Err(Errcode::NamespacesError(4));
+// Several ranges can be given, one per line.
+pub fn handle_child_uid_map(
+    pid: Pid,
+    fd: RawFd,
+    uid_map: &[IdMap],
+    gid_map: &[IdMap],
+    rootless: bool,
+) -> Result<(), Errcode> {
+    let has_userns = match recv_message(fd)? {
+        Some(Message::UidMapRequest { has_userns }) => has_userns,
+        message => return Err(unexpected(message)),
+    };
+    if !has_userns {
+        log::info!("No user namespace set up from child process");
+    } else if rootless {
+        write_id_map_rootless(pid, "uid_map", NEWUIDMAP, uid_map, getuid().as_raw())?;
+        write_id_map_rootless(pid, "gid_map", NEWGIDMAP, gid_map, getgid().as_raw())?;
+    } else {
+        write_id_map(pid, "uid_map", uid_map)?;
+        write_id_map(pid, "gid_map", gid_map)?;
+    }
+    Ok(())
+}
+
+// The child waits for this signal after sending its user namespace, so its namespaces
+// are all set up and it can't exit in between
+pub fn resume_child(fd: RawFd) -> Result<(), Errcode> {
+    log::debug!("Child UID/GID map done, sending signal to child to continue...");
+    send_message(fd, Message::UidMapDone)
+}
+
+// How a namespace of the container is obtained: a new one, the one of the host,
+// or an existing one, from another container or a bind-mounted namespace file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamespaceMode {
+    Private,
+    Host,
+    Container(String),
+    Path(PathBuf),
+}
+
+// Parses none|private, host, container:<id> or ns:<path>
+impl FromStr for NamespaceMode {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<NamespaceMode, Errcode> {
+        match s {
+            "none" | "private" => Ok(NamespaceMode::Private),
+            "host" => Ok(NamespaceMode::Host),
+            _ => {
+                if let Some(id) = s.strip_prefix("container:") {
+                    Ok(NamespaceMode::Container(id.to_string()))
+                } else if let Some(path) = s.strip_prefix("ns:") {
+                    Ok(NamespaceMode::Path(PathBuf::from(path)))
+                } else {
+                    Err(Errcode::ArgumentInvalid("namespace"))
+                }
             }
-        } else {
-            return Err(Errcode::NamespacesError(5));
         }
+    }
+}
 
-        if let Ok(mut gid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "gid_map")) {
-            if let Err(_) =
-                gid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes())
-            {
-                return Err(Errcode::NamespacesError(6));
+impl NamespaceMode {
+    // The namespace of another container is the one of its process
+    fn resolve(self, ns: &str) -> Result<NamespaceMode, Errcode> {
+        let id = match self {
+            NamespaceMode::Container(id) => id,
+            mode => return Ok(mode),
+        };
+        let state = ContainerState::load(&id)?;
+        match state.pid {
+            Some(pid) if state.is_active() => Ok(NamespaceMode::Path(PathBuf::from(format!(
+                "/proc/{}/ns/{}",
+                pid, ns
+            )))),
+            _ => {
+                log::error!(
+                    "Cannot share the {} namespace, container {} isn't running",
+                    ns,
+                    id
+                );
+                Err(Errcode::NamespacesError(12))
             }
-        } else {
-            return Err(Errcode::NamespacesError(7));
         }
-    } else {
-        log::info!("No user namespace set up from child process");
     }
+}
 
-    log::debug!("Child UID/GID map done, sending signal to child to continue...");
-    send_boolean(fd, false)
+// The namespaces which can be shared, so several containers can form a pod.
+// The mount, cgroup and user namespaces always belong to the container.
+#[derive(Debug, Clone)]
+pub struct Namespaces {
+    pub net: NamespaceMode,
+    pub pid: NamespaceMode,
+    pub ipc: NamespaceMode,
+    pub uts: NamespaceMode,
+    // Without offsets, the container uses the time namespace of the host
+    pub time: Option<TimeOffsets>,
+}
+
+impl Namespaces {
+    pub fn resolve(self) -> Result<Namespaces, Errcode> {
+        Ok(Namespaces {
+            net: self.net.resolve("net")?,
+            pid: self.pid.resolve("pid")?,
+            ipc: self.ipc.resolve("ipc")?,
+            uts: self.uts.resolve("uts")?,
+            time: self.time,
+        })
+    }
+
+    // Flags of the namespaces created by clone
+    pub fn clone_flags(&self) -> CloneFlags {
+        let mut flags = CloneFlags::empty();
+        for (mode, flag) in [
+            (&self.net, CloneFlags::CLONE_NEWNET),
+            (&self.pid, CloneFlags::CLONE_NEWPID),
+            (&self.ipc, CloneFlags::CLONE_NEWIPC),
+            (&self.uts, CloneFlags::CLONE_NEWUTS),
+        ] {
+            if *mode == NamespaceMode::Private {
+                flags.insert(flag);
+            }
+        }
+        flags
+    }
+
+    // Joining a PID namespace only applies to the children, so the intermediate process of
+    // the spawner joins it before clone
+    pub fn join_pid(&self) -> Result<(), Errcode> {
+        join_namespace(&self.pid, CloneFlags::CLONE_NEWPID)
+    }
+
+    pub fn unshare_time(&self) -> Result<(), Errcode> {
+        match &self.time {
+            Some(offsets) => unshare_time(offsets),
+            None => Ok(()),
+        }
+    }
+
+    // Called by the child before setting up anything else
+    pub fn join(&self) -> Result<(), Errcode> {
+        join_namespace(&self.net, CloneFlags::CLONE_NEWNET)?;
+        join_namespace(&self.ipc, CloneFlags::CLONE_NEWIPC)?;
+        join_namespace(&self.uts, CloneFlags::CLONE_NEWUTS)
+    }
+}
+
+fn join_namespace(mode: &NamespaceMode, flag: CloneFlags) -> Result<(), Errcode> {
+    let path = match mode {
+        NamespaceMode::Path(path) => path,
+        _ => return Ok(()),
+    };
+    log::debug!("Joining namespace {}", path.display());
+    let fd = match open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            log::error!("Cannot open namespace {}: {}", path.display(), e);
+            return Err(os_error(Errcode::NamespacesError(13), e));
+        }
+    };
+    let res = setns(fd, flag);
+    if let Err(e) = close(fd) {
+        log::error!("Cannot close namespace {}: {}", path.display(), e);
+    }
+    if let Err(e) = res {
+        log::error!("Cannot join namespace {}: {}", path.display(), e);
+        return Err(os_error(Errcode::NamespacesError(14), e));
+    }
+    Ok(())
+}
+
+// The types of namespace files in /proc/<pid>/ns
+const NS_TYPES: [&str; 8] = ["cgroup", "ipc", "mnt", "net", "pid", "time", "user", "uts"];
+
+// A namespace of the container to bind-mount, so it outlives its processes and other tools
+// (ip netns exec, nsenter) can join it. Without path, it goes in the state directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistNs {
+    pub ns: String,
+    pub path: Option<PathBuf>,
+}
+
+// Parses <type>[=<path>]
+impl FromStr for PersistNs {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<PersistNs, Errcode> {
+        let (ns, path) = match s.split_once('=') {
+            Some((ns, path)) => (ns, Some(PathBuf::from(path))),
+            None => (s, None),
+        };
+        if !NS_TYPES.contains(&ns) {
+            return Err(Errcode::ArgumentInvalid("persist-ns"));
+        }
+        Ok(PersistNs {
+            ns: ns.to_string(),
+            path,
+        })
+    }
+}
+
+pub fn persist_namespace(pid: Pid, ns: &str, path: &Path) -> Result<(), Errcode> {
+    log::debug!("Persisting {} namespace at {}", ns, path.display());
+    if let Some(dir) = path.parent() {
+        if let Err(e) = create_dir_all(dir) {
+            log::error!("Cannot create directory {}: {}", dir.display(), e);
+            return Err(Errcode::NamespacesError(17));
+        }
+    }
+    // The bind mount needs an existing file as target
+    if let Err(e) = File::create(path) {
+        log::error!("Cannot create {}: {}", path.display(), e);
+        return Err(Errcode::NamespacesError(17));
+    }
+    let source = PathBuf::from(format!("/proc/{}/ns/{}", pid.as_raw(), ns));
+    if let Err(e) =
+        mount::<PathBuf, Path, str, str>(Some(&source), path, None, MsFlags::MS_BIND, None)
+    {
+        log::error!(
+            "Cannot bind {} to {}: {}",
+            source.display(),
+            path.display(),
+            e
+        );
+        return Err(Errcode::NamespacesError(18));
+    }
+    Ok(())
+}
+
+pub fn release_namespace(path: &Path) -> Result<(), Errcode> {
+    log::debug!("Releasing namespace {}", path.display());
+    if let Err(e) = umount2(path, MntFlags::MNT_DETACH) {
+        log::error!("Cannot unmount {}: {}", path.display(), e);
+        return Err(Errcode::NamespacesError(19));
+    }
+    if let Err(e) = remove_file(path) {
+        log::error!("Cannot remove {}: {}", path.display(), e);
+        return Err(Errcode::NamespacesError(19));
+    }
+    Ok(())
+}
+
+// Namespaces joined by crabcan exec, in order. The user namespace gives the capabilities
+// to join the others it owns, the mount namespace changes the root directory.
+const JOIN_ORDER: [(&str, libc::c_int); 8] = [
+    ("user", libc::CLONE_NEWUSER),
+    ("cgroup", libc::CLONE_NEWCGROUP),
+    ("ipc", libc::CLONE_NEWIPC),
+    ("uts", libc::CLONE_NEWUTS),
+    ("net", libc::CLONE_NEWNET),
+    ("pid", libc::CLONE_NEWPID),
+    ("time", CLONE_NEWTIME),
+    ("mnt", libc::CLONE_NEWNS),
+];
+
+// Joins all the namespaces of a process, only the children of the caller enter the PID and
+// time namespaces. The user namespace can't be joined by a multithreaded process.
+pub fn enter_namespaces(pid: Pid) -> Result<(), Errcode> {
+    // Opened first, /proc isn't the one of the host anymore once in the mount namespace
+    let mut namespaces = Vec::new();
+    for (ns, flag) in JOIN_ORDER.iter() {
+        let path = PathBuf::from(format!("/proc/{}/ns/{}", pid.as_raw(), ns));
+        // Without time namespaces, the kernel has no file for them
+        if !path.exists() || is_same_file(&path, &format!("/proc/self/ns/{}", ns)) {
+            continue;
+        }
+        match open(&path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+            Ok(fd) => namespaces.push((fd, *flag, path)),
+            Err(e) => {
+                log::error!("Cannot open namespace {}: {}", path.display(), e);
+                for (fd, _, _) in namespaces {
+                    let _ = close(fd);
+                }
+                return Err(os_error(Errcode::NamespacesError(20), e));
+            }
+        }
+    }
+    // Like nsenter, the namespaces which can be are joined before the user namespace:
+    // without rootless mode, the child unshares its user namespace after creating the others,
+    // only the capabilities of crabcan in the user namespace of the host allow to join them
+    let mut result = Ok(());
+    for first in [true, false] {
+        for (fd, flag, path) in namespaces.iter_mut() {
+            if *fd < 0 || (first && *flag == libc::CLONE_NEWUSER) {
+                continue;
+            }
+            if unsafe { libc::setns(*fd, *flag) } != 0 {
+                if first {
+                    continue;
+                }
+                let e = Errno::last();
+                log::error!("Cannot join namespace {}: {}", path.display(), e);
+                result = Err(os_error(Errcode::NamespacesError(21), e));
+                break;
+            }
+            if let Err(e) = close(*fd) {
+                log::error!("Cannot close namespace {}: {}", path.display(), e);
+            }
+            *fd = -1;
+        }
+    }
+    for (fd, _, _) in namespaces.iter().filter(|(fd, _, _)| *fd >= 0) {
+        let _ = close(*fd);
+    }
+    result
+}
+
+// A namespace shared with the host can't be joined again
+fn is_same_file(path: &Path, other: &str) -> bool {
+    match (stat(path), stat(other)) {
+        (Ok(a), Ok(b)) => a.st_dev == b.st_dev && a.st_ino == b.st_ino,
+        _ => false,
+    }
+}
+
+// Not defined by nix nor libc yet
+const CLONE_NEWTIME: libc::c_int = 0x80;
+const TIMENS_OFFSETS: &str = "/proc/self/timens_offsets";
+
+// Offsets in seconds of the clocks of the container, relative to the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeOffsets {
+    pub monotonic: i64,
+    pub boottime: i64,
+}
+
+// Parses monotonic=SECS,boottime=SECS, a missing clock keeps the time of the host
+impl FromStr for TimeOffsets {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<TimeOffsets, Errcode> {
+        let mut offsets = TimeOffsets::default();
+        for field in s.split(',') {
+            let (clock, secs) = match field.split_once('=') {
+                Some((clock, secs)) => (clock, secs.parse()),
+                None => return Err(Errcode::ArgumentInvalid("time-offset")),
+            };
+            match (clock, secs) {
+                ("monotonic", Ok(secs)) => offsets.monotonic = secs,
+                ("boottime", Ok(secs)) => offsets.boottime = secs,
+                _ => return Err(Errcode::ArgumentInvalid("time-offset")),
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+// Like the PID namespace, only the children enter the new time namespace.
+// Its offsets can only be written until a first process is created inside it.
+fn unshare_time(offsets: &TimeOffsets) -> Result<(), Errcode> {
+    log::debug!("Creating time namespace with offsets {:?}", offsets);
+    if unsafe { libc::unshare(CLONE_NEWTIME) } != 0 {
+        let e = Errno::last();
+        log::error!("Cannot create time namespace: {}", e);
+        return Err(os_error(Errcode::NamespacesError(15), e));
+    }
+    let content = format!(
+        "monotonic {} 0\nboottime {} 0",
+        offsets.monotonic, offsets.boottime
+    );
+    if let Err(e) = write(TIMENS_OFFSETS, content) {
+        log::error!("Cannot write {}: {}", TIMENS_OFFSETS, e);
+        return Err(io_error(Errcode::NamespacesError(16), &e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(inside: u32, outside: u32, count: u32) -> IdMap {
+        IdMap {
+            inside,
+            outside,
+            count,
+        }
+    }
+
+    // A subid file of the test, in which the user running the tests has no range
+    fn subid_file(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("crabcan-{}-{}", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn id_maps_parse() {
+        assert_eq!(
+            "0:100000:65536".parse::<IdMap>().ok(),
+            Some(map(0, 100000, 65536))
+        );
+        assert_eq!("1:1000:1".parse::<IdMap>().ok(), Some(map(1, 1000, 1)));
+        let malformed = [
+            "",
+            "0:1000",
+            "0:1000:1:1",
+            "0:1000:",
+            "a:1000:1",
+            "-1:1000:1",
+            "0 1000 1",
+            "0:4294967296:1",
+        ];
+        for s in malformed {
+            assert!(s.parse::<IdMap>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn id_maps_overlap_on_host_ids() {
+        let a = map(0, 1000, 100);
+        assert!(a.overlaps(&map(0, 1099, 1)));
+        assert!(a.overlaps(&map(5, 900, 101)));
+        assert!(a.overlaps(&map(0, 1050, 10)));
+        assert!(!a.overlaps(&map(0, 1100, 100)));
+        assert!(!a.overlaps(&map(0, 900, 100)));
+        // Only the host IDs count
+        assert!(!a.overlaps(&map(1000, 0, 100)));
+        assert!(map(0, u32::MAX, 1).overlaps(&map(0, u32::MAX - 1, 2)));
+
+        let taken = [map(0, 10000, 2000)];
+        assert!(check_id_maps(&[map(0, 12000, 2000)], &taken).is_ok());
+        assert!(matches!(
+            check_id_maps(&[map(0, 12000, 10), map(10, 11999, 1)], &taken),
+            Err(Errcode::NamespacesError(8))
+        ));
+    }
+
+    #[test]
+    fn free_block_skips_taken_ranges() {
+        let ranges = [(100000, 5000), (200000, 2000)];
+        let taken = [map(0, 100000, 1), map(0, 104000, 10)];
+        assert_eq!(free_block(&ranges, &taken, 0), Some(map(0, 102000, 2000)));
+        let taken = [map(0, 100000, 5000)];
+        assert_eq!(free_block(&ranges, &taken, 1), Some(map(1, 200000, 2000)));
+        // A range too small for a whole block isn't used
+        assert_eq!(free_block(&[(100000, 1999)], &[], 0), None);
+        assert_eq!(free_block(&[(u32::MAX - 1000, 1000)], &[], 0), None);
+    }
+
+    #[test]
+    fn free_block_exhausted() {
+        let ranges = [(100000, 4000)];
+        let taken = [map(0, 100000, 2000), map(0, 102000, 2000)];
+        assert_eq!(free_block(&ranges, &taken, 0), None);
+        assert_eq!(free_block(&[], &[], 0), None);
+    }
+
+    #[test]
+    fn default_pool_leaves_other_users_ranges() {
+        let path = subid_file(
+            "subuid-others",
+            "crabcan-test-a:10000:4000\ncrabcan-test-b:16000:2000\n",
+        );
+        let path = path.to_str().unwrap();
+        let maps = allocate_id_maps(path, &[]).unwrap();
+        assert_eq!(maps, vec![map(0, 14000, 2000)]);
+        let maps = allocate_id_maps(path, &[map(0, 14000, 2000)]).unwrap();
+        assert_eq!(maps, vec![map(0, 18000, 2000)]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn default_pool_holds_many_containers() {
+        let path = subid_file("subuid-pool", "");
+        let path = path.to_str().unwrap();
+        let pool = USERNS_COUNT * USERNS_DEFAULT_BLOCKS;
+        let last = USERNS_OFFSET + pool - USERNS_COUNT;
+        let taken = [map(0, USERNS_OFFSET, pool - USERNS_COUNT)];
+        let maps = allocate_id_maps(path, &taken).unwrap();
+        assert_eq!(maps, vec![map(0, last, USERNS_COUNT)]);
+
+        let taken = [map(0, USERNS_OFFSET, pool)];
+        assert!(matches!(
+            allocate_id_maps(path, &taken),
+            Err(Errcode::NamespacesError(9))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn offsets(monotonic: i64, boottime: i64) -> TimeOffsets {
+        TimeOffsets {
+            monotonic,
+            boottime,
+        }
+    }
+
+    #[test]
+    fn time_offsets_parse() {
+        let valid = [
+            ("monotonic=86400", offsets(86400, 0)),
+            ("boottime=60", offsets(0, 60)),
+            ("monotonic=1,boottime=2", offsets(1, 2)),
+            ("boottime=2,monotonic=1", offsets(1, 2)),
+            ("monotonic=-3600,boottime=-1", offsets(-3600, -1)),
+            ("monotonic=+5", offsets(5, 0)),
+            ("monotonic=0", offsets(0, 0)),
+            // The last value of a clock is kept
+            ("monotonic=1,monotonic=2", offsets(2, 0)),
+        ];
+        for (s, expected) in valid {
+            assert_eq!(s.parse::<TimeOffsets>().ok(), Some(expected), "{}", s);
+        }
+    }
+
+    #[test]
+    fn time_offsets_rejected() {
+        let invalid = [
+            "",
+            "monotonic",
+            "monotonic=",
+            "monotonic=1s",
+            "monotonic=1.5",
+            "realtime=10",
+            "Monotonic=10",
+            "monotonic=1,",
+            "monotonic=1;boottime=2",
+            "monotonic = 1",
+            "boottime=9223372036854775808",
+        ];
+        for s in invalid {
+            assert!(s.parse::<TimeOffsets>().is_err(), "{}", s);
+        }
+    }
 }
@@ -0,0 +1,242 @@
+use crate::errors::Errcode;
+use crate::resources::{cgroup_dir, list_cgroup};
+use crate::state::ContainerState;
+
+use nix::unistd::{sysconf, Pid, SysconfVar};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{read, read_dir, read_to_string};
+
+// The UID shown for a host UID without mapping, like the kernel does inside a user namespace
+const OVERFLOW_UID: u32 = 65534;
+
+// A process of a container, as seen from inside it
+#[derive(Debug, Serialize)]
+struct Process {
+    pid: i32,
+    // 0 for the first process, whose parent is outside the container
+    ppid: i32,
+    uid: u32,
+    state: char,
+    // Seconds spent on the CPU, in user and kernel mode
+    cpu_time: f64,
+    command: String,
+}
+
+// What is read from /proc for a process of the host
+struct ProcStatus {
+    host_pid: i32,
+    host_ppid: i32,
+    // PID in the innermost PID namespace of the process, the one of the container
+    ns_pid: i32,
+    host_uid: u32,
+    state: char,
+    ticks: u64,
+    // Resident memory in bytes
+    memory: u64,
+    command: String,
+}
+
+// The resources used by the processes of a container, given by its shim
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContainerStats {
+    pub pids: usize,
+    // Seconds spent on the CPU by the running processes
+    pub cpu_time: f64,
+    // Resident memory in bytes, as accounted by the cgroup if the container has one
+    pub memory: u64,
+}
+
+// Lists the processes of a container: the processes of its cgroup, or without cgroup
+// the process of the container and its descendants
+pub fn ps(id: &str, json: bool) -> Result<(), Errcode> {
+    let state = ContainerState::load(id)?;
+    let pid = match state.pid {
+        Some(pid) if state.is_active() => Pid::from_raw(pid),
+        _ => {
+            log::error!("Container {} isn't running", id);
+            return Err(Errcode::ContainerError(8));
+        }
+    };
+    let statuses = container_processes(id, pid)?;
+    let ns_pids: HashMap<i32, i32> = statuses.iter().map(|s| (s.host_pid, s.ns_pid)).collect();
+    let ticks_per_second = ticks_per_second();
+    let mut processes: Vec<Process> = statuses
+        .into_iter()
+        .map(|status| Process {
+            pid: status.ns_pid,
+            ppid: ns_pids.get(&status.host_ppid).copied().unwrap_or(0),
+            uid: state
+                .uid_map
+                .iter()
+                .find_map(|map| map.inside_id(status.host_uid))
+                .unwrap_or(OVERFLOW_UID),
+            state: status.state,
+            cpu_time: status.ticks as f64 / ticks_per_second,
+            command: status.command,
+        })
+        .collect();
+    processes.sort_by_key(|p| p.pid);
+
+    if json {
+        return match serde_json::to_string_pretty(&processes) {
+            Ok(out) => {
+                println!("{}", out);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Cannot serialize processes of container {}: {}", id, e);
+                Err(Errcode::PsError(1))
+            }
+        };
+    }
+    println!(
+        "{:>7} {:>7} {:>7} {:<5} {:>8} CMD",
+        "PID", "PPID", "UID", "STAT", "TIME"
+    );
+    for p in processes.iter() {
+        println!(
+            "{:>7} {:>7} {:>7} {:<5} {:>8} {}",
+            p.pid,
+            p.ppid,
+            p.uid,
+            p.state,
+            format_time(p.cpu_time as u64),
+            p.command
+        );
+    }
+    Ok(())
+}
+
+pub fn container_stats(id: &str, pid: Pid) -> Result<ContainerStats, Errcode> {
+    let statuses = container_processes(id, pid)?;
+    let ticks: u64 = statuses.iter().map(|s| s.ticks).sum();
+    let memory = match read_to_string(cgroup_dir(id).join("memory.current")) {
+        Ok(memory) => memory.trim().parse().ok(),
+        Err(_) => None,
+    };
+    Ok(ContainerStats {
+        pids: statuses.len(),
+        cpu_time: ticks as f64 / ticks_per_second(),
+        memory: memory.unwrap_or_else(|| statuses.iter().map(|s| s.memory).sum()),
+    })
+}
+
+fn container_processes(id: &str, pid: Pid) -> Result<Vec<ProcStatus>, Errcode> {
+    let pids = match list_cgroup(id)? {
+        Some(pids) => pids,
+        None => descendants(pid)?,
+    };
+    // A process may have exited since the listing
+    Ok(pids.into_iter().filter_map(read_status).collect())
+}
+
+fn ticks_per_second() -> f64 {
+    match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(ticks)) if ticks > 0 => ticks as f64,
+        _ => 100.0,
+    }
+}
+
+// The descendants of a process, found through the parents of every process of the host
+fn descendants(pid: Pid) -> Result<Vec<Pid>, Errcode> {
+    let entries = match read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("Cannot list the processes of the host: {}", e);
+            return Err(Errcode::PsError(0));
+        }
+    };
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for entry in entries.flatten() {
+        let child = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<i32>().ok())
+        {
+            Some(child) => child,
+            None => continue,
+        };
+        if let Some((_, fields)) = read_stat(child) {
+            if let Some(ppid) = fields.get(1).and_then(|p| p.parse().ok()) {
+                children.entry(ppid).or_default().push(child);
+            }
+        }
+    }
+    let mut pids = Vec::new();
+    let mut queue = VecDeque::from(vec![pid.as_raw()]);
+    while let Some(pid) = queue.pop_front() {
+        pids.push(Pid::from_raw(pid));
+        queue.extend(children.remove(&pid).unwrap_or_default());
+    }
+    Ok(pids)
+}
+
+fn read_status(pid: Pid) -> Option<ProcStatus> {
+    let (name, fields) = read_stat(pid.as_raw())?;
+    let status = read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let field = |key: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .map(|value| value.split_whitespace().collect::<Vec<&str>>())
+    };
+    // Without PID namespaces (before Linux 4.1), the PID is the one of the host
+    let ns_pid = match field("NSpid:") {
+        Some(pids) => pids.last()?.parse().ok()?,
+        None => pid.as_raw(),
+    };
+    // The real UID of the process
+    let host_uid = field("Uid:")?.first()?.parse().ok()?;
+    let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    // In kB, zombies and kernel threads have none
+    let memory = match field("VmRSS:") {
+        Some(rss) => rss.first()?.parse::<u64>().ok()? * 1024,
+        None => 0,
+    };
+    // Kernel threads and zombies have no command line
+    let cmdline = read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let command = match cmdline.is_empty() {
+        true => format!("[{}]", name),
+        false => cmdline
+            .split(|c| *c == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect::<Vec<String>>()
+            .join(" "),
+    };
+    Some(ProcStatus {
+        host_pid: pid.as_raw(),
+        host_ppid: fields.get(1)?.parse().ok()?,
+        ns_pid,
+        host_uid,
+        state: fields.first()?.chars().next()?,
+        ticks,
+        memory,
+        command,
+    })
+}
+
+// The name of the process and the fields of /proc/<pid>/stat after it, starting with
+// the state. The name is between parentheses and may contain spaces or parentheses.
+fn read_stat(pid: i32) -> Option<(String, Vec<String>)> {
+    let stat = read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    let name = stat.get(start + 1..end)?.to_string();
+    let fields = stat[end + 1..]
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    Some((name, fields))
+}
+
+// As ps prints it: [days-]hours:minutes:seconds
+fn format_time(seconds: u64) -> String {
+    let (days, hours) = (seconds / 86400, seconds / 3600 % 24);
+    let time = format!("{:02}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60);
+    match days {
+        0 => time,
+        _ => format!("{}-{}", days, time),
+    }
+}
@@ -1,48 +1,106 @@
-use crate::errors::Errcode;
+use crate::errors::{io_error, Errcode};
 
 use cgroups_rs::cgroup_builder::CgroupBuilder;
 use cgroups_rs::hierarchies::V2;
-use cgroups_rs::{MaxValue, CgroupPid};
+use cgroups_rs::{Cgroup, MaxValue, CgroupPid};
 use rlimit::{setrlimit, Resource};
-use nix::unistd::Pid;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{access, AccessFlags, Pid};
 
-use std::fs::{canonicalize, remove_dir};
+use std::fs::{canonicalize, read_to_string, remove_dir, write};
+use std::path::{Path, PathBuf};
 use std::convert::TryInto;
+use std::os::unix::io::RawFd;
+use std::thread::sleep;
+use std::time::Duration;
 
 //                      K       M       G
 const KMEM_LIMIT: i64 = 1024 * 1024 * 1024;
 const MEM_LIMIT: i64 = KMEM_LIMIT;
 const MAX_PID: MaxValue = MaxValue::Value(64);
 const NOFILE_RLIMIT: u64 = 64;
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Processes forking while they are killed one by one are killed in the next round
+const KILL_ROUNDS: usize = 100;
+const KILL_ROUND_DELAY: Duration = Duration::from_millis(10);
 
-pub fn restrict_resources(hostname: &String, pid: Pid) -> Result<(), Errcode>{
+// The limits are set up before the child exists, it is then created inside its cgroup
+// with the returned descriptor of the cgroup directory
+pub fn restrict_resources(hostname: &String) -> Result<Option<RawFd>, Errcode>{
     log::debug!("Restricting resources for hostname {}", hostname);
 
-    let cgs = CgroupBuilder::new(hostname)
+    // The child inherits the limits of the parent
+    set_rlimits()?;
+
+    // Without root, cgroups can only be created if the hierarchy was delegated to the user
+    if access(CGROUP_ROOT, AccessFlags::W_OK).is_err() {
+        log::warn!("Cgroups aren't delegated to this user, no memory, CPU or PID limits are applied");
+        return Ok(None);
+    }
+
+    CgroupBuilder::new(hostname)
         .cpu().shares(256).done()
         .memory().kernel_memory_limit(KMEM_LIMIT).memory_hard_limit(MEM_LIMIT).done()
         .pid().maximum_number_of_processes(MAX_PID).done()
         .blkio().weight(50).done()
         .build(Box::new(V2::new()));
 
-    let pid : u64 = pid.as_raw().try_into().unwrap();
-    if let Err(_) = cgs.add_task(CgroupPid::from(pid)) {
-        return Err(Errcode::ResourcesError(0));
-    };
+    let path = format!("{}/{}", CGROUP_ROOT, hostname);
+    match open(path.as_str(), OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty()) {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) => {
+            log::error!("Cannot open cgroup {}: {}", path, e);
+            Err(Errcode::ResourcesError(4))
+        }
+    }
+}
 
-    if let Err(_) = setrlimit(Resource::NOFILE, NOFILE_RLIMIT, NOFILE_RLIMIT){
-        return Err(Errcode::ResourcesError(1));
+// Also applied by crabcan exec to the processes it runs in a container
+pub fn set_rlimits() -> Result<(), Errcode> {
+    if let Err(e) = setrlimit(Resource::NOFILE, NOFILE_RLIMIT, NOFILE_RLIMIT){
+        return Err(io_error(Errcode::ResourcesError(1), &e));
     }
+    Ok(())
+}
 
+// Moves the calling process into the cgroup of a container, if it has one
+pub fn join_cgroup(hostname: &str) -> Result<(), Errcode> {
+    let procs = Path::new(CGROUP_ROOT).join(hostname).join("cgroup.procs");
+    if !procs.exists() {
+        return Ok(());
+    }
+    // 0 is the process writing it
+    if let Err(e) = write(&procs, "0") {
+        log::error!("Cannot join cgroup {}: {}", hostname, e);
+        return Err(io_error(Errcode::ResourcesError(6), &e));
+    }
+    Ok(())
+}
+
+// Used when the child couldn't be created inside its cgroup
+pub fn add_to_cgroup(hostname: &str, pid: Pid) -> Result<(), Errcode>{
+    let cgs = Cgroup::load(Box::new(V2::new()), hostname);
+    let pid : u64 = pid.as_raw().try_into().unwrap();
+    if let Err(e) = cgs.add_task(CgroupPid::from(pid)) {
+        log::error!("Cannot add process {} to cgroup {}: {}", pid, hostname, e);
+        return Err(Errcode::ResourcesError(0));
+    };
     Ok(())
 }
 
 pub fn clean_cgroups(hostname: &String) -> Result<(), Errcode>{
     log::debug!("Cleaning cgroups");
-    match canonicalize(format!("/sys/fs/cgroup/{}/", hostname)){
+    // No cgroup was created without delegation
+    if !Path::new(&format!("{}/{}", CGROUP_ROOT, hostname)).exists() {
+        return Ok(());
+    }
+    match canonicalize(format!("{}/{}/", CGROUP_ROOT, hostname)){
         Ok(d) => {
-            if let Err(_) = remove_dir(d) {
-                return Err(Errcode::ResourcesError(2));
+            if let Err(e) = remove_dir(&d) {
+                log::error!("Cannot remove cgroup {}: {}", d.display(), e);
+                return Err(io_error(Errcode::ResourcesError(2), &e));
             }
         },
         Err(e) => {
@@ -51,4 +109,61 @@ pub fn clean_cgroups(hostname: &String) -> Result<(), Errcode>{
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+pub fn cgroup_dir(hostname: &str) -> PathBuf {
+    Path::new(CGROUP_ROOT).join(hostname)
+}
+
+// The processes of the cgroup of a container, None if it has no cgroup
+pub fn list_cgroup(hostname: &str) -> Result<Option<Vec<Pid>>, Errcode> {
+    let procs = cgroup_dir(hostname).join("cgroup.procs");
+    if !procs.exists() {
+        return Ok(None);
+    }
+    match read_to_string(&procs) {
+        Ok(pids) => Ok(Some(pids.lines().filter_map(|p| p.parse().ok()).map(Pid::from_raw).collect())),
+        Err(e) => {
+            log::error!("Cannot list processes of cgroup {}: {}", hostname, e);
+            Err(Errcode::ResourcesError(7))
+        }
+    }
+}
+
+// Kills every process of the cgroup, with cgroup.kill if the kernel has it (5.14),
+// else by listing its processes until none is left.
+// Returns false if the container has no cgroup.
+pub fn kill_cgroup(hostname: &str) -> Result<bool, Errcode> {
+    let path = Path::new(CGROUP_ROOT).join(hostname);
+    let procs = path.join("cgroup.procs");
+    if !procs.exists() {
+        return Ok(false);
+    }
+    let kill_file = path.join("cgroup.kill");
+    if kill_file.exists() {
+        if let Err(e) = write(&kill_file, "1") {
+            log::error!("Cannot kill cgroup {}: {}", path.display(), e);
+            return Err(Errcode::ResourcesError(5));
+        }
+        return Ok(true);
+    }
+    for _ in 0..KILL_ROUNDS {
+        let pids = match read_to_string(&procs) {
+            Ok(pids) => pids,
+            Err(e) => {
+                log::error!("Cannot list processes of cgroup {}: {}", path.display(), e);
+                return Err(Errcode::ResourcesError(5));
+            }
+        };
+        let pids: Vec<Pid> = pids.lines().filter_map(|p| p.parse().ok()).map(Pid::from_raw).collect();
+        if pids.is_empty() {
+            return Ok(true);
+        }
+        for pid in pids {
+            // The process may have exited since the listing
+            let _ = kill(pid, Signal::SIGKILL);
+        }
+        sleep(KILL_ROUND_DELAY);
+    }
+    log::error!("Processes of cgroup {} are still running", path.display());
+    Err(Errcode::ResourcesError(5))
+}
@@ -0,0 +1,184 @@
+use crate::errors::Errcode;
+use crate::state::ExitReason;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+// The delay before a restart doubles each time, like docker, up to the maximum
+const BACKOFF_MIN: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+// A process running for this long didn't crash at startup, the delay starts over
+const BACKOFF_RESET: Duration = Duration::from_secs(10);
+
+// When the crabcan process running a container starts its command again once it exited.
+// A container stopped with crabcan stop, or whose crabcan process is asked to terminate,
+// is never restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    No,
+    // When the command exits with another code than 0, at most the given number of times
+    OnFailure(Option<u32>),
+    Always,
+    // As always, unless the process was sent a signal with crabcan kill
+    UnlessStopped,
+}
+
+// Parses no, on-failure[:max], always or unless-stopped
+impl FromStr for RestartPolicy {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<RestartPolicy, Errcode> {
+        match s.split_once(':') {
+            None => match s {
+                "no" => Ok(RestartPolicy::No),
+                "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+                "always" => Ok(RestartPolicy::Always),
+                "unless-stopped" => Ok(RestartPolicy::UnlessStopped),
+                _ => Err(Errcode::ArgumentInvalid("restart")),
+            },
+            Some(("on-failure", max)) => match max.parse() {
+                Ok(max) => Ok(RestartPolicy::OnFailure(Some(max))),
+                Err(_) => Err(Errcode::ArgumentInvalid("restart")),
+            },
+            Some(_) => Err(Errcode::ArgumentInvalid("restart")),
+        }
+    }
+}
+
+impl RestartPolicy {
+    // restarts is the number of times the container was already restarted,
+    // signaled tells if the process was sent a signal with crabcan kill
+    pub fn should_restart(&self, reason: ExitReason, restarts: u32, signaled: bool) -> bool {
+        match (self, reason) {
+            (RestartPolicy::No, _) | (_, ExitReason::Stopped) | (_, ExitReason::Failed) => false,
+            (RestartPolicy::OnFailure(_), ExitReason::Exited(0)) => false,
+            (RestartPolicy::OnFailure(Some(max)), _) => restarts < *max,
+            (RestartPolicy::OnFailure(None), _) => true,
+            (RestartPolicy::Always, _) => true,
+            // Killed by the signal, or exited after handling it, e.g. through --init
+            (RestartPolicy::UnlessStopped, _) => !signaled,
+        }
+    }
+}
+
+// The delay before the next restart of a process which ran for the given time
+pub fn next_backoff(previous: Option<Duration>, ran: Duration) -> Duration {
+    match previous {
+        Some(previous) if ran < BACKOFF_RESET => (previous * 2).min(BACKOFF_MAX),
+        _ => BACKOFF_MIN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_parse() {
+        let valid = [
+            ("no", RestartPolicy::No),
+            ("on-failure", RestartPolicy::OnFailure(None)),
+            ("on-failure:3", RestartPolicy::OnFailure(Some(3))),
+            ("on-failure:0", RestartPolicy::OnFailure(Some(0))),
+            ("always", RestartPolicy::Always),
+            ("unless-stopped", RestartPolicy::UnlessStopped),
+        ];
+        for (s, policy) in valid {
+            assert_eq!(s.parse::<RestartPolicy>().ok(), Some(policy), "{}", s);
+        }
+        let invalid = [
+            "",
+            "yes",
+            "on-failure:",
+            "on-failure:-1",
+            "on-failure:x",
+            "always:3",
+            "no:1",
+            "On-Failure",
+        ];
+        for s in invalid {
+            assert!(s.parse::<RestartPolicy>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn restarts_by_policy_and_reason() {
+        use ExitReason::*;
+        use RestartPolicy::*;
+
+        // Policy, reason, then if the process is restarted without and with crabcan kill.
+        // An init exits with 137 once its command was killed by SIGKILL.
+        let table = [
+            (No, Exited(0), false, false),
+            (No, Exited(1), false, false),
+            (No, Exited(137), false, false),
+            (No, Signaled(9), false, false),
+            (No, Stopped, false, false),
+            (No, Failed, false, false),
+            (OnFailure(None), Exited(0), false, false),
+            (OnFailure(None), Exited(1), true, true),
+            (OnFailure(None), Exited(137), true, true),
+            (OnFailure(None), Signaled(9), true, true),
+            (OnFailure(None), Stopped, false, false),
+            (OnFailure(None), Failed, false, false),
+            (Always, Exited(0), true, true),
+            (Always, Exited(1), true, true),
+            (Always, Exited(137), true, true),
+            (Always, Signaled(9), true, true),
+            (Always, Stopped, false, false),
+            (Always, Failed, false, false),
+            (UnlessStopped, Exited(0), true, false),
+            (UnlessStopped, Exited(1), true, false),
+            (UnlessStopped, Exited(137), true, false),
+            (UnlessStopped, Signaled(9), true, false),
+            (UnlessStopped, Stopped, false, false),
+            (UnlessStopped, Failed, false, false),
+        ];
+        for (policy, reason, unsignaled, signaled) in table {
+            for (sent, expected) in [(false, unsignaled), (true, signaled)] {
+                assert_eq!(
+                    policy.should_restart(reason, 0, sent),
+                    expected,
+                    "{:?} {:?} signaled: {}",
+                    policy,
+                    reason,
+                    sent
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn on_failure_stops_at_the_maximum() {
+        let policy = RestartPolicy::OnFailure(Some(2));
+        for signaled in [false, true] {
+            assert!(policy.should_restart(ExitReason::Exited(1), 0, signaled));
+            assert!(policy.should_restart(ExitReason::Signaled(9), 1, signaled));
+            assert!(!policy.should_restart(ExitReason::Exited(1), 2, signaled));
+            assert!(!policy.should_restart(ExitReason::Exited(0), 0, signaled));
+        }
+        let never = RestartPolicy::OnFailure(Some(0));
+        assert!(!never.should_restart(ExitReason::Exited(1), 0, false));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let quick = Duration::from_secs(1);
+        assert_eq!(next_backoff(None, quick), BACKOFF_MIN);
+        let mut backoff = next_backoff(None, quick);
+        for _ in 0..20 {
+            let next = next_backoff(Some(backoff), quick);
+            assert_eq!(next, (backoff * 2).min(BACKOFF_MAX));
+            backoff = next;
+        }
+        assert_eq!(backoff, BACKOFF_MAX);
+        assert_eq!(next_backoff(Some(BACKOFF_MAX), quick), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn backoff_starts_over_after_a_long_run() {
+        assert_eq!(next_backoff(Some(BACKOFF_MAX), BACKOFF_RESET), BACKOFF_MIN);
+        let just_under = BACKOFF_RESET - Duration::from_millis(1);
+        assert_eq!(next_backoff(Some(BACKOFF_MIN), just_under), BACKOFF_MIN * 2);
+    }
+}
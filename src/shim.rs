@@ -0,0 +1,365 @@
+use crate::attach::AttachServer;
+use crate::cli::RunArgs;
+use crate::container::run_container;
+use crate::daemon::notify;
+use crate::errors::Errcode;
+use crate::ps::{container_stats, ContainerStats};
+use crate::signals::SignalForwarder;
+use crate::state::{container_dir, ContainerState};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::signal::Signal;
+use nix::sys::wait::waitpid;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::remove_file;
+use std::io::{Read, Write};
+use std::mem::zeroed;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+
+// Socket of the crabcan process running a container, in its state directory
+const CONTROL_SOCKET: &str = "control.sock";
+// A request or a response is a single line of JSON
+const MESSAGE_MAX_SIZE: usize = 4096;
+
+// What a client asks to the crabcan process running a container
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    // The signal is sent to the process of the container
+    Kill { signal: i32 },
+    // The connection becomes a client of the attach server once answered
+    Attach,
+    Stats,
+    // Sent by crabcan stop before signaling the process, which is then not restarted
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Done,
+    Attached,
+    Stats(ContainerStats),
+}
+
+// The process running a container started by crabcan create or with --detach. The daemon
+// re-executes crabcan as a shim, which is the subreaper of the container: the processes
+// left behind by the container are reparented to the shim instead of init. It owns the
+// stdio of the container, records its exit code and cleans it up once it exited.
+pub fn run_shim(mut args: RunArgs, hold: bool, pipe: RawFd) -> Result<(), Errcode> {
+    // Only kept open across the execution of the shim, the container must not inherit it
+    if let Err(e) = fcntl(pipe, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        log::error!(
+            "Cannot set close-on-exec on the pipe of the daemon: {:?}",
+            e
+        );
+    }
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } < 0 {
+        log::error!("Cannot make the shim a subreaper: {:?}", Errno::last());
+        let e = Errcode::ShimError(3);
+        notify(pipe, Err(&e));
+        return Err(e);
+    }
+    // The outputs and the input of the container are only available through crabcan attach
+    args.detach = true;
+    run_container(args, hold, Some(pipe))
+}
+
+// Children waited for by the thread which created them, the reapers leave them alone
+static HELPERS: Mutex<Vec<Pid>> = Mutex::new(Vec::new());
+// Notified once the creator of a helper waited for it
+static HELPER_WAITED: Condvar = Condvar::new();
+
+fn helpers() -> MutexGuard<'static, Vec<Pid>> {
+    // Every update is a single push or removal, the list is valid even if a thread panicked
+    match HELPERS.lock() {
+        Ok(helpers) => helpers,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Creates a helper process, pid gives its PID from the result of create. The reapers look at
+// an exited child only once create returned, so they can't take a helper not registered yet.
+pub fn spawn_helper<T, E, C, P>(create: C, pid: P) -> Result<T, E>
+where
+    C: FnOnce() -> Result<T, E>,
+    P: FnOnce(&T) -> Option<Pid>,
+{
+    let mut helpers = helpers();
+    let res = create();
+    if let Some(helper) = res.as_ref().ok().and_then(pid) {
+        helpers.push(helper);
+    }
+    res
+}
+
+// Called by the creator of a helper once it waited for it
+pub fn helper_waited(pid: Pid) {
+    helpers().retain(|helper| *helper != pid);
+    HELPER_WAITED.notify_all();
+}
+
+// A child which exited and wasn't waited for yet, None if there is none
+fn exited_child(flags: libc::c_int) -> Option<Pid> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { zeroed() };
+        let res = unsafe { libc::waitid(libc::P_ALL, 0, &mut info, flags | libc::WNOWAIT) };
+        if res < 0 {
+            match Errno::last() {
+                Errno::EINTR => continue,
+                _ => return None,
+            }
+        }
+        // With WNOHANG, the PID is 0 when no child exited
+        return match unsafe { info.si_pid() } {
+            0 => None,
+            pid => Some(Pid::from_raw(pid)),
+        };
+    }
+}
+
+// Reaps an exited child, unless it is a helper. waitid returns the same helper again until
+// its creator waited for it, which it does right away, the other children are seen once it did.
+// False if the child couldn't be waited for, waitid would return it again.
+fn reap_exited(pid: Pid) -> bool {
+    let mut helpers = helpers();
+    if !helpers.contains(&pid) {
+        return match waitpid(pid, None) {
+            Ok(status) => {
+                log::debug!("Reaped orphan: {:?}", status);
+                true
+            }
+            Err(e) => {
+                log::error!("Cannot reap orphan {}: {:?}", pid, e);
+                false
+            }
+        };
+    }
+    while helpers.contains(&pid) {
+        helpers = match HELPER_WAITED.wait(helpers) {
+            Ok(helpers) => helpers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+    true
+}
+
+// The orphans of the container are reaped as they exit. The process of the container is
+// left to wait_child, the reaper stops once it exited.
+pub fn reap_orphans(child: Pid) {
+    thread::spawn(move || loop {
+        match exited_child(libc::WEXITED) {
+            Some(pid) if pid != child && reap_exited(pid) => continue,
+            _ => return,
+        }
+    });
+}
+
+// The orphans which exited at the same time as the process of the container
+pub fn reap_zombies() {
+    while let Some(pid) = exited_child(libc::WEXITED | libc::WNOHANG) {
+        if !reap_exited(pid) {
+            return;
+        }
+    }
+}
+
+pub fn control_socket(id: &str) -> PathBuf {
+    container_dir(id).join(CONTROL_SOCKET)
+}
+
+// Removed with the other files of a stopped container
+pub fn remove_control_socket(id: &str) {
+    let path = control_socket(id);
+    if path.exists() {
+        if let Err(e) = remove_file(&path) {
+            log::error!("Cannot remove control socket {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Answers the requests of crabcan kill, attach and stats until crabcan exits. Without
+// attach server, the terminal of the container was sent to a console socket.
+pub fn listen_control(
+    id: &str,
+    signals: SignalForwarder,
+    attach: Option<AttachServer>,
+) -> Result<(), Errcode> {
+    let path = control_socket(id);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Cannot listen on {}: {}", path.display(), e);
+            return Err(Errcode::ShimError(0));
+        }
+    };
+    let id = id.to_string();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            match client {
+                Ok(client) => {
+                    let (id, signals, attach) = (id.clone(), signals.clone(), attach.clone());
+                    thread::spawn(move || serve(&id, client, &signals, attach));
+                }
+                Err(e) => log::error!("Cannot accept a client: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(
+    id: &str,
+    mut client: UnixStream,
+    signals: &SignalForwarder,
+    attach: Option<AttachServer>,
+) {
+    let request = match read_message::<Request>(&mut client) {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = write_message(&mut client, &Err::<Response, Errcode>(e));
+            return;
+        }
+    };
+    log::debug!("Control request: {:?}", request);
+    let response = match request {
+        Request::Kill { signal } => match Signal::try_from(signal) {
+            Ok(signal) => signals.signal_child(signal).map(|_| Response::Done),
+            Err(_) => Err(Errcode::ArgumentInvalid("signal")),
+        },
+        Request::Attach if attach.is_some() => Ok(Response::Attached),
+        Request::Attach => {
+            log::error!(
+                "The terminal of container {} was sent to a console socket",
+                id
+            );
+            Err(Errcode::AttachError(1))
+        }
+        Request::Stop => {
+            signals.stop();
+            Ok(Response::Done)
+        }
+        Request::Stats => ContainerState::load(id).and_then(|state| match state.pid {
+            Some(pid) => container_stats(id, Pid::from_raw(pid)).map(Response::Stats),
+            None => Err(Errcode::ContainerError(5)),
+        }),
+    };
+    let attached = matches!(response, Ok(Response::Attached));
+    if write_message(&mut client, &response).is_err() {
+        return;
+    }
+    if let (true, Some(attach)) = (attached, attach) {
+        attach.add(client);
+    }
+}
+
+// Sends a request to the crabcan process running a container. The connection is returned
+// with the response, an attached client goes on using it.
+pub fn request(id: &str, request: &Request) -> Result<(Response, UnixStream), Errcode> {
+    let path = control_socket(id);
+    let mut socket = match UnixStream::connect(&path) {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("Cannot connect to the process of container {}: {}", id, e);
+            return Err(Errcode::ShimError(1));
+        }
+    };
+    write_message(&mut socket, request)?;
+    match read_message::<Result<Response, Errcode>>(&mut socket)? {
+        Ok(response) => Ok((response, socket)),
+        Err(e) => {
+            log::error!("Request to container {} failed: {:?}", id, e);
+            Err(e)
+        }
+    }
+}
+
+pub fn unexpected_response(response: Response) -> Errcode {
+    log::error!("Unexpected response from the container: {:?}", response);
+    Errcode::ShimError(2)
+}
+
+// Sends a signal to the process of a container through its crabcan process
+pub fn kill(id: &str, signal: Signal) -> Result<(), Errcode> {
+    let request = Request::Kill {
+        signal: signal as i32,
+    };
+    match request_active(id, &request)? {
+        Response::Done => Ok(()),
+        response => Err(unexpected_response(response)),
+    }
+}
+
+// Prints the resources used by the processes of a container
+pub fn stats(id: &str) -> Result<(), Errcode> {
+    let stats = match request_active(id, &Request::Stats)? {
+        Response::Stats(stats) => stats,
+        response => return Err(unexpected_response(response)),
+    };
+    match serde_json::to_string_pretty(&stats) {
+        Ok(out) => {
+            println!("{}", out);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Cannot serialize stats of container {}: {}", id, e);
+            Err(Errcode::StateError(2))
+        }
+    }
+}
+
+fn request_active(id: &str, request: &Request) -> Result<Response, Errcode> {
+    let state = ContainerState::load(id)?;
+    if !state.is_active() {
+        log::error!("Container {} isn't running", id);
+        return Err(Errcode::ContainerError(8));
+    }
+    Ok(self::request(id, request)?.0)
+}
+
+fn write_message<T: Serialize>(socket: &mut UnixStream, message: &T) -> Result<(), Errcode> {
+    let mut line = match serde_json::to_vec(message) {
+        Ok(line) => line,
+        Err(e) => {
+            log::error!("Cannot serialize control message: {}", e);
+            return Err(Errcode::ShimError(2));
+        }
+    };
+    line.push(b'\n');
+    if let Err(e) = socket.write_all(&line) {
+        log::error!("Cannot send control message: {}", e);
+        return Err(Errcode::ShimError(2));
+    }
+    Ok(())
+}
+
+// Read byte by byte, what follows the line belongs to the attached client
+fn read_message<T: for<'de> Deserialize<'de>>(socket: &mut UnixStream) -> Result<T, Errcode> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.len() < MESSAGE_MAX_SIZE {
+        match socket.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) => {
+                log::error!("Cannot read control message: {}", e);
+                return Err(Errcode::ShimError(2));
+            }
+        }
+    }
+    match serde_json::from_slice(&line) {
+        Ok(message) => Ok(message),
+        Err(e) => {
+            log::error!("Invalid control message: {}", e);
+            Err(Errcode::ShimError(2))
+        }
+    }
+}
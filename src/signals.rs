@@ -0,0 +1,202 @@
+use crate::child::ChildProcess;
+use crate::errors::{os_error, Errcode};
+use crate::ipc::release_exec_fifo;
+use crate::tty::resize;
+
+use nix::sys::signal::{SigSet, Signal};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+// Signals received by crabcan which are forwarded to the container
+const FORWARDED_SIGNALS: [Signal; 7] = [
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+];
+// Received before the command of the container is executed, they stop its creation instead
+const TERMINATING_SIGNALS: [Signal; 4] = [
+    Signal::SIGINT,
+    Signal::SIGTERM,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+];
+
+#[derive(Default)]
+struct Target {
+    // A copy of the child, with its own pidfd
+    child: Option<ChildProcess>,
+    // Set once the command of the container is executed
+    started: bool,
+    interrupted: bool,
+    // Fifo the parent waits on while the container is created
+    exec_fifo: Option<PathBuf>,
+    // Master of the terminal of the container, resized instead of forwarding SIGWINCH
+    tty: Option<RawFd>,
+    // The container is stopped with crabcan stop or crabcan is terminating, it isn't restarted
+    stopping: bool,
+    // The process was sent a signal with crabcan kill
+    signaled: bool,
+}
+
+// The signals are handled by a thread of their own, so crabcan isn't killed by them
+// and can always clean the container once its process exited
+#[derive(Clone)]
+pub struct SignalForwarder {
+    target: Arc<Mutex<Target>>,
+}
+
+impl SignalForwarder {
+    // Has to be called before any other thread is spawned, they inherit the blocked signals
+    // and only the forwarder waits for them
+    pub fn spawn() -> Result<SignalForwarder, Errcode> {
+        let signals = forwarded_signals();
+        block_signals()?;
+        let forwarder = SignalForwarder {
+            target: Arc::new(Mutex::new(Target::default())),
+        };
+        let target = forwarder.clone();
+        thread::spawn(move || target.forward(signals));
+        Ok(forwarder)
+    }
+
+    pub fn set_child(&self, child: ChildProcess, exec_fifo: Option<PathBuf>) {
+        let mut target = self.lock();
+        target.child = Some(child);
+        // A restarted child executes the command again
+        target.started = false;
+        target.exec_fifo = exec_fifo;
+    }
+
+    pub fn set_started(&self) {
+        let mut target = self.lock();
+        target.started = true;
+        target.exec_fifo = None;
+    }
+
+    pub fn set_tty(&self, master: RawFd) {
+        self.lock().tty = Some(master);
+    }
+
+    // The child is reaped, its PID can be reused
+    pub fn clear_child(&self) {
+        self.lock().child = None;
+    }
+
+    // Sends a signal asked through the control socket, as if crabcan received it
+    pub fn signal_child(&self, signal: Signal) -> Result<(), Errcode> {
+        let mut target = self.lock();
+        target.signaled = true;
+        match &target.child {
+            Some(child) => child.signal(signal),
+            None => {
+                log::error!("No container process to send {} to", signal);
+                Err(Errcode::ContainerError(5))
+            }
+        }
+    }
+
+    // Asked by crabcan stop before it signals the process itself
+    pub fn stop(&self) {
+        self.lock().stopping = true;
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.lock().stopping
+    }
+
+    // Whether the process which exited was sent a signal with crabcan kill,
+    // the next process of the container starts without it
+    pub fn take_signaled(&self) -> bool {
+        let mut target = self.lock();
+        let signaled = target.signaled;
+        target.signaled = false;
+        signaled
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.lock().interrupted
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Target> {
+        // Every update of the target is a single assignment, it is valid even if a thread panicked
+        match self.target.lock() {
+            Ok(target) => target,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn forward(&self, signals: SigSet) {
+        loop {
+            let signal = match signals.wait() {
+                Ok(signal) => signal,
+                Err(e) => {
+                    log::error!("Cannot wait for signals: {:?}", e);
+                    return;
+                }
+            };
+            let mut target = self.lock();
+            if !target.started && TERMINATING_SIGNALS.contains(&signal) {
+                log::info!(
+                    "Received {}, stopping the creation of the container",
+                    signal
+                );
+                target.interrupted = true;
+                // Nothing is running yet in the container, and the child can't handle the signal
+                if let Some(child) = &target.child {
+                    let _ = child.signal(Signal::SIGKILL);
+                }
+                // Wakes up the parent waiting for crabcan start
+                if let Some(fifo) = target.exec_fifo.take() {
+                    let _ = release_exec_fifo(&fifo);
+                }
+                continue;
+            }
+            if let (Signal::SIGWINCH, Some(master)) = (signal, target.tty) {
+                resize(master);
+                continue;
+            }
+            if TERMINATING_SIGNALS.contains(&signal) {
+                target.stopping = true;
+            }
+            match &target.child {
+                Some(child) => {
+                    log::debug!("Forwarding {} to the container", signal);
+                    let _ = child.signal(signal);
+                }
+                None => log::debug!("Received {}, no container to forward it to", signal),
+            }
+        }
+    }
+}
+
+// Also blocked before the spawner is forked, it is left to crabcan to handle them
+pub fn block_signals() -> Result<(), Errcode> {
+    if let Err(e) = forwarded_signals().thread_block() {
+        log::error!("Cannot block the forwarded signals: {:?}", e);
+        return Err(Errcode::ContainerError(3));
+    }
+    Ok(())
+}
+
+// The child inherits the blocked signals from the parent, the command must not
+pub fn unblock_signals() -> Result<(), Errcode> {
+    if let Err(e) = forwarded_signals().thread_unblock() {
+        log::error!("Cannot unblock signals in the container: {:?}", e);
+        return Err(os_error(Errcode::ChildProcessError(6), e));
+    }
+    Ok(())
+}
+
+fn forwarded_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    for signal in FORWARDED_SIGNALS.iter() {
+        signals.add(*signal);
+    }
+    signals
+}
@@ -0,0 +1,222 @@
+use crate::child::{generate_child_process, report, ChildProcess, SetupFailure};
+use crate::config::ContainerOpts;
+use crate::errors::{os_error, take_os_error, Errcode};
+use crate::ipc::{
+    generate_socket_pair, recv_fd, recv_frame, recv_message_with_fd, send_fd, send_message_with_fd,
+    unexpected, Message,
+};
+use crate::resources::set_rlimits;
+
+use nix::errno::Errno;
+use nix::sys::wait::waitpid;
+use nix::unistd::{close, fork, getpid, ForkResult, Pid};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// The processes of the container are cloned by a process forked while crabcan still has a
+// single thread. Cloned from crabcan once its threads run, the child could copy a lock held
+// by one of them, of the allocator or of the logger, and wait for it forever during its
+// setup. The spawner never runs a thread, and creates the children as children of crabcan.
+#[derive(Debug)]
+pub struct Spawner {
+    pid: Pid,
+    socket: RawFd,
+}
+
+impl Spawner {
+    // Must be called before any thread is spawned. The spawner closes the descriptors only
+    // crabcan keeps, and exits once crabcan closes its socket.
+    pub fn fork(config: &ContainerOpts, parent_fds: &[RawFd]) -> Result<Spawner, Errcode> {
+        let (socket, spawner_socket) = generate_socket_pair()?;
+        match unsafe { fork() } {
+            Ok(ForkResult::Parent { child }) => {
+                if let Err(e) = close(spawner_socket) {
+                    log::error!("Unable to close the socket of the spawner: {:?}", e);
+                }
+                log::debug!("Spawner PID: {}", child);
+                Ok(Spawner { pid: child, socket })
+            }
+            Ok(ForkResult::Child) => {
+                for fd in std::iter::once(&socket).chain(parent_fds.iter()) {
+                    if let Err(e) = close(*fd) {
+                        log::error!("Unable to close fd {} in the spawner: {:?}", fd, e);
+                    }
+                }
+                let code = serve(spawner_socket, config.clone());
+                unsafe { libc::_exit(code) }
+            }
+            Err(e) => {
+                log::error!("Cannot fork the spawner: {:?}", e);
+                let _ = close(socket);
+                let _ = close(spawner_socket);
+                Err(Errcode::ChildProcessError(8))
+            }
+        }
+    }
+
+    // Creates a process of the container, set up through socket. The spawner only gets
+    // copies of the socket and of the cgroup directory.
+    pub fn spawn(&self, socket: RawFd, cgroup: Option<RawFd>) -> Result<ChildProcess, Errcode> {
+        let request = Message::Spawn {
+            cgroup: cgroup.is_some(),
+        };
+        send_message_with_fd(self.socket, request, Some(socket))?;
+        if let Some(fd) = cgroup {
+            send_fd(self.socket, fd)?;
+        }
+        match recv_message_with_fd(self.socket)? {
+            Some((Message::Spawned { pid, intermediate }, pidfd)) => {
+                if let Err(e) = waitpid(Pid::from_raw(intermediate), None) {
+                    log::debug!("Cannot wait for the intermediate process: {:?}", e);
+                }
+                Ok(ChildProcess::new(Pid::from_raw(pid), pidfd))
+            }
+            Some((message, received)) => {
+                if let Some(fd) = received {
+                    let _ = close(fd);
+                }
+                Err(unexpected(Some(message)))
+            }
+            None => Err(unexpected(None)),
+        }
+    }
+
+    // Once no process is created anymore, the spawner doesn't keep its copies of the
+    // terminal and of the pipes of the container
+    pub fn close(self) {
+        if let Err(e) = close(self.socket) {
+            log::error!("Unable to close the socket of the spawner: {:?}", e);
+        }
+        if let Err(e) = waitpid(self.pid, None) {
+            log::debug!("Cannot wait for the spawner: {:?}", e);
+        }
+    }
+}
+
+// Creates a child for each request, until crabcan closes the socket
+fn serve(socket: RawFd, mut config: ContainerOpts) -> i32 {
+    // The children inherit the limits of the spawner
+    if let Err(e) = set_rlimits() {
+        log::error!("Cannot set the limits of the spawner: {:?}", e);
+        return 1;
+    }
+    loop {
+        let (child_socket, cgroup) = match recv_message_with_fd(socket) {
+            Ok(Some((Message::Spawn { cgroup }, Some(child_socket)))) => (child_socket, cgroup),
+            Ok(None) => return 0,
+            Ok(Some((message, received))) => {
+                if let Some(fd) = received {
+                    let _ = close(fd);
+                }
+                unexpected(Some(message));
+                return 1;
+            }
+            Err(_) => return 1,
+        };
+        let cgroup = match cgroup {
+            true => match recv_fd(socket) {
+                Ok(fd) => Some(fd),
+                Err(_) => return 1,
+            },
+            false => None,
+        };
+        config.fd = child_socket;
+        let spawned = clone_intermediate(&config, cgroup);
+        // Only the child keeps its end of the socket, so crabcan sees it closing on exec
+        for fd in std::iter::once(child_socket).chain(cgroup) {
+            if let Err(e) = close(fd) {
+                log::error!("Unable to close fd {} in the spawner: {:?}", fd, e);
+            }
+        }
+        match spawned {
+            Ok((spawned, pidfd)) => {
+                let sent = send_message_with_fd(socket, spawned, pidfd);
+                if let Some(fd) = pidfd {
+                    let _ = close(fd);
+                }
+                if sent.is_err() {
+                    return 1;
+                }
+            }
+            Err(errcode) => report(
+                socket,
+                SetupFailure {
+                    stage: "clone",
+                    errcode,
+                    errno: take_os_error(),
+                },
+            ),
+        }
+    }
+}
+
+// The child joins a PID namespace and gets its time namespace through a process cloned for
+// each child, the spawner never enters them and each child gets its own time namespace.
+// Like the child, the intermediate process is a child of crabcan, it answers with the
+// message the spawner sends to crabcan, its failure included.
+fn clone_intermediate(
+    config: &ContainerOpts,
+    cgroup: Option<RawFd>,
+) -> Result<(Message, Option<RawFd>), Errcode> {
+    let (reply, intermediate_reply) = generate_socket_pair()?;
+    // Without a stack, clone duplicates the process like fork
+    let flags = libc::CLONE_PARENT as libc::c_ulong;
+    let stack = ptr::null_mut::<libc::c_void>();
+    let res = Errno::result(unsafe { libc::syscall(libc::SYS_clone, flags, stack) });
+    if let Ok(0) = res {
+        let _ = close(reply);
+        let code = intermediate(config, cgroup, intermediate_reply);
+        unsafe { libc::_exit(code) }
+    }
+    let _ = close(intermediate_reply);
+    let replied = match res {
+        Ok(_) => recv_frame(reply),
+        Err(e) => {
+            log::error!("Cannot create the intermediate process: {}", e);
+            Err(os_error(Errcode::ChildProcessError(0), e))
+        }
+    };
+    let _ = close(reply);
+    match replied? {
+        Some((spawned @ Message::Spawned { .. }, pidfd)) => Ok((spawned, pidfd)),
+        Some((failure @ Message::SetupError { .. }, None)) => Ok((failure, None)),
+        Some((message, received)) => {
+            if let Some(fd) = received {
+                let _ = close(fd);
+            }
+            Err(unexpected(Some(message)))
+        }
+        None => Err(unexpected(None)),
+    }
+}
+
+fn intermediate(config: &ContainerOpts, cgroup: Option<RawFd>, reply: RawFd) -> i32 {
+    let child = config
+        .namespaces
+        .join_pid()
+        .and_then(|_| config.namespaces.unshare_time())
+        .and_then(|_| generate_child_process(config.clone(), cgroup));
+    match child {
+        Ok(child) => {
+            let spawned = Message::Spawned {
+                pid: child.pid.as_raw(),
+                intermediate: getpid().as_raw(),
+            };
+            match send_message_with_fd(reply, spawned, child.pidfd()) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+        Err(errcode) => {
+            report(
+                reply,
+                SetupFailure {
+                    stage: "clone",
+                    errcode,
+                    errno: take_os_error(),
+                },
+            );
+            1
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use crate::child::ExitStatus;
+use crate::errors::Errcode;
+use crate::namespaces::IdMap;
+use crate::user::ContainerUser;
+
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::{geteuid, Pid};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{create_dir_all, read_dir, read_to_string, rename, write, File};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Every container gets a directory /run/crabcan/<id> holding its state and logs,
+// where <id> is the hostname of the container
+pub const STATE_DIR: &str = "/run/crabcan";
+// Directory used in rootless mode, inside $XDG_RUNTIME_DIR
+const ROOTLESS_STATE_DIR: &str = "crabcan";
+const STATE_FILE: &str = "state.json";
+const LOCK_FILE: &str = ".lock";
+// Fifo opened by crabcan start to release a created container
+const EXEC_FIFO: &str = "exec.fifo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    // The container process is being set up
+    Creating,
+    // The setup is finished, the command waits for crabcan start
+    Created,
+    Running,
+    // The process exited, its crabcan process waits to start it again
+    Restarting,
+    Stopped,
+}
+
+// Why the last process of a container ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    // The command exited with the code
+    Exited(i32),
+    // The command was killed by the signal
+    Signaled(i32),
+    // The container was stopped with crabcan stop, or crabcan was asked to terminate
+    Stopped,
+    // The process couldn't be set up again to restart the container
+    Failed,
+}
+
+impl ExitReason {
+    // From the status given by wait. An init exiting with 128 + the signal which killed the
+    // command exited, it wasn't killed itself.
+    pub fn new(status: ExitStatus, stopped: bool) -> ExitReason {
+        match (stopped, status) {
+            (true, _) => ExitReason::Stopped,
+            (false, ExitStatus::Exited(code)) => ExitReason::Exited(code),
+            (false, ExitStatus::Signaled(signal)) => ExitReason::Signaled(signal),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub status: Status,
+    pub pid: Option<i32>,
+    pub command: String,
+    pub rootfs: PathBuf,
+    // Seconds since the epoch
+    pub created: u64,
+    #[serde(default)]
+    pub uid_map: Vec<IdMap>,
+    #[serde(default)]
+    pub gid_map: Vec<IdMap>,
+    // Bind mounts of the namespaces, removed when the container stops
+    #[serde(default)]
+    pub persisted_ns: Vec<PathBuf>,
+    // User of the command, also the default user of crabcan exec
+    #[serde(default)]
+    pub user: Option<ContainerUser>,
+    // The command runs in a terminal, crabcan attach puts its own terminal in raw mode
+    #[serde(default)]
+    pub tty: bool,
+    // Exit code of the process once stopped, 128 + the signal if it was killed
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub exit_reason: Option<ExitReason>,
+    // Number of times the process was started again by the restart policy
+    #[serde(default)]
+    pub restart_count: u32,
+}
+
+impl ContainerState {
+    pub fn new(id: &str, command: String, rootfs: PathBuf) -> ContainerState {
+        let created = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs(),
+            Err(_) => 0,
+        };
+        ContainerState {
+            id: id.to_string(),
+            status: Status::Creating,
+            pid: None,
+            command,
+            rootfs,
+            created,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            persisted_ns: Vec::new(),
+            user: None,
+            tty: false,
+            exit_code: None,
+            exit_reason: None,
+            restart_count: 0,
+        }
+    }
+
+    // A container that isn't stopped still uses its resources, unless its process died
+    // without crabcan noticing it
+    pub fn is_active(&self) -> bool {
+        match (self.status, self.pid) {
+            (Status::Stopped, _) => false,
+            // Its crabcan process is still running, waiting to restart it
+            (Status::Restarting, _) => true,
+            (_, Some(pid)) => kill(Pid::from_raw(pid), None).is_ok(),
+            (_, None) => true,
+        }
+    }
+
+    pub fn load(id: &str) -> Result<ContainerState, Errcode> {
+        let path = container_dir(id).join(STATE_FILE);
+        let content = match read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Cannot read state of container {}: {}", id, e);
+                return Err(Errcode::StateError(0));
+            }
+        };
+        match serde_json::from_str(&content) {
+            Ok(state) => Ok(state),
+            Err(e) => {
+                log::error!("Cannot parse state of container {}: {}", id, e);
+                Err(Errcode::StateError(1))
+            }
+        }
+    }
+
+    // The state is written to a temporary file first, so a reader never sees half of it
+    pub fn save(&self) -> Result<(), Errcode> {
+        let dir = create_container_dir(&self.id)?;
+        let content = match serde_json::to_string_pretty(self) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Cannot serialize state of container {}: {}", self.id, e);
+                return Err(Errcode::StateError(2));
+            }
+        };
+        let tmp = dir.join(format!("{}.tmp", STATE_FILE));
+        if let Err(e) = write(&tmp, content) {
+            log::error!("Cannot write state of container {}: {}", self.id, e);
+            return Err(Errcode::StateError(3));
+        }
+        if let Err(e) = rename(&tmp, dir.join(STATE_FILE)) {
+            log::error!("Cannot write state of container {}: {}", self.id, e);
+            return Err(Errcode::StateError(3));
+        }
+        Ok(())
+    }
+}
+
+// Unprivileged users can't write /run/crabcan, each one gets its own state directory
+pub fn state_dir() -> PathBuf {
+    if geteuid().is_root() {
+        return PathBuf::from(STATE_DIR);
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(ROOTLESS_STATE_DIR),
+        None => env::temp_dir().join(format!("crabcan-{}", geteuid())),
+    }
+}
+
+pub fn container_dir(id: &str) -> PathBuf {
+    state_dir().join(id)
+}
+
+pub fn exec_fifo(id: &str) -> PathBuf {
+    container_dir(id).join(EXEC_FIFO)
+}
+
+pub fn create_container_dir(id: &str) -> Result<PathBuf, Errcode> {
+    let dir = container_dir(id);
+    if let Err(e) = create_dir_all(&dir) {
+        log::error!("Cannot create state directory {}: {}", dir.display(), e);
+        return Err(Errcode::StateError(4));
+    }
+    Ok(dir)
+}
+
+// All the containers having a state directory
+pub fn list_containers() -> Result<Vec<ContainerState>, Errcode> {
+    let dir = state_dir();
+    let entries = match read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            log::error!("Cannot list containers in {}: {}", dir.display(), e);
+            return Err(Errcode::StateError(6));
+        }
+    };
+    let mut containers = Vec::new();
+    for entry in entries.flatten() {
+        if !entry.path().join(STATE_FILE).exists() {
+            continue;
+        }
+        // A broken state is logged and skipped, it shouldn't prevent using other containers
+        if let Some(id) = entry.file_name().to_str() {
+            if let Ok(state) = ContainerState::load(id) {
+                containers.push(state);
+            }
+        }
+    }
+    Ok(containers)
+}
+
+// Serializes the allocation of resources shared between containers,
+// the lock is released when the returned file is dropped
+pub fn lock() -> Result<File, Errcode> {
+    let dir = state_dir();
+    let path = dir.join(LOCK_FILE);
+    if let Err(e) = create_dir_all(&dir) {
+        log::error!("Cannot create state directory {}: {}", dir.display(), e);
+        return Err(Errcode::StateError(4));
+    }
+    let file = match File::create(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            log::error!("Cannot open lock file {}: {}", path.display(), e);
+            return Err(Errcode::StateError(7));
+        }
+    };
+    if let Err(e) = flock(file.as_raw_fd(), FlockArg::LockExclusive) {
+        log::error!("Cannot lock {}: {}", path.display(), e);
+        return Err(Errcode::StateError(7));
+    }
+    Ok(file)
+}
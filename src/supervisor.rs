@@ -0,0 +1,555 @@
+// With the SECCOMP_RET_USER_NOTIF action, a syscall made inside the container is suspended and
+// reported on a listener fd. The child hands this fd to the parent process, where the supervisor
+// reads each notification and lets a handler decide what to do with it:
+// let the syscall continue, make it fail with an errno, or do the work itself and return a value.
+use crate::errors::Errcode;
+use crate::shim::{helper_waited, spawn_helper};
+use crate::syscalls::LearnedSyscalls;
+
+use serde::{Deserialize, Serialize};
+
+use libseccomp::{notify_id_valid, ScmpNotifReq, ScmpNotifResp, ScmpNotifRespFlags};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, MsFlags};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::{major, minor, mknod, Mode, SFlag};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{chown, close, fchdir, fork, ForkResult, Gid, Uid};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+// File of the state directory where the syscalls denied to the container are logged
+pub const DENIED_LOG: &str = "seccomp.log";
+
+// Longest string read from the memory of the container process
+const PATH_MAX: usize = 4096;
+// Pages are at least this big, an aligned read never goes past the end of a mapping
+const READ_CHUNK: usize = 4096;
+
+// Character devices that can be created inside the container (major, minor)
+const MKNOD_ALLOWED: [(u64, u64); 6] = [
+    (1, 3), // null
+    (1, 5), // zero
+    (1, 7), // full
+    (1, 8), // random
+    (1, 9), // urandom
+    (5, 0), // tty
+];
+
+// Filesystems the container may mount, with the options it may give them. proc is left out as
+// its pid namespace would be the one of the supervisor, and not the one of the container.
+// The mount is done with the privileges of the host: sizes, inode counts or owners can't be
+// chosen by the container, only permissions, given in octal.
+const MOUNT_ALLOWED_FS: [(&str, &[&str]); 4] = [
+    ("tmpfs", &["mode"]),
+    ("mqueue", &[]),
+    ("sysfs", &[]),
+    ("devpts", &["newinstance", "mode", "ptmxmode"]),
+];
+const MOUNT_ALLOWED_FLAGS: MsFlags = MsFlags::from_bits_truncate(
+    MsFlags::MS_RDONLY.bits()
+        | MsFlags::MS_NOSUID.bits()
+        | MsFlags::MS_NODEV.bits()
+        | MsFlags::MS_NOEXEC.bits()
+        | MsFlags::MS_NOATIME.bits()
+        | MsFlags::MS_NODIRATIME.bits()
+        | MsFlags::MS_RELATIME.bits()
+        | MsFlags::MS_STRICTATIME.bits(),
+);
+
+// What the container process gets back from the intercepted syscall
+#[derive(Debug)]
+pub enum Verdict {
+    // The syscall is executed by the kernel as if it wasn't intercepted
+    Continue,
+    // The syscall fails with this errno
+    Deny(i32),
+    // The syscall was done by the supervisor, and returns this value
+    Return(i64),
+}
+
+pub trait NotifyHandler: Send {
+    // Tells if the handler wants to take care of this syscall
+    fn handles(&self, syscall: &str) -> bool;
+    fn handle(&mut self, notif: &Notification) -> Verdict;
+}
+
+// Syscalls that can be intercepted from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intercept {
+    Mknod,
+    Mount,
+    Sethostname,
+}
+
+impl Intercept {
+    pub fn syscalls(&self) -> &'static [&'static str] {
+        match self {
+            Intercept::Mknod => &["mknod", "mknodat"],
+            Intercept::Mount => &["mount"],
+            Intercept::Sethostname => &["sethostname"],
+        }
+    }
+
+    pub fn handler(&self) -> Box<dyn NotifyHandler> {
+        match self {
+            Intercept::Mknod => Box::new(MknodHandler),
+            Intercept::Mount => Box::new(MountHandler),
+            Intercept::Sethostname => Box::new(SethostnameHandler),
+        }
+    }
+}
+
+impl FromStr for Intercept {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Intercept, Errcode> {
+        match s {
+            "mknod" => Ok(Intercept::Mknod),
+            "mount" => Ok(Intercept::Mount),
+            "sethostname" => Ok(Intercept::Sethostname),
+            _ => Err(Errcode::ArgumentInvalid("intercept")),
+        }
+    }
+}
+
+// A syscall waiting for an answer from the supervisor
+pub struct Notification<'a> {
+    fd: RawFd,
+    pub req: &'a ScmpNotifReq,
+    pub syscall: &'a str,
+}
+
+impl<'a> Notification<'a> {
+    pub fn arg(&self, ind: usize) -> u64 {
+        self.req.data.args[ind]
+    }
+
+    // Reads a NUL terminated string at address addr in the memory of the process
+    pub fn read_string(&self, addr: u64) -> Result<CString, i32> {
+        let mem = match File::open(format!("/proc/{}/mem", self.req.pid)) {
+            Ok(f) => f,
+            Err(_) => return Err(libc::EFAULT),
+        };
+        // Read until the NUL, the string may end right before an unmapped page
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            if buf.len() >= PATH_MAX {
+                return Err(libc::ENAMETOOLONG);
+            }
+            let at = addr + buf.len() as u64;
+            let size = (READ_CHUNK - at as usize % READ_CHUNK).min(PATH_MAX - buf.len());
+            let len = match mem.read_at(&mut chunk[..size], at) {
+                Ok(0) | Err(_) => return Err(libc::EFAULT),
+                Ok(len) => len,
+            };
+            match chunk[..len].iter().position(|c| *c == 0) {
+                Some(end) => {
+                    buf.extend_from_slice(&chunk[..end]);
+                    break;
+                }
+                None => buf.extend_from_slice(&chunk[..len]),
+            }
+        }
+
+        // The process could have been replaced by another one reusing its pid while we read
+        if notify_id_valid(self.fd, self.req.id).is_err() {
+            return Err(libc::ESRCH);
+        }
+        CString::new(buf).map_err(|_| libc::EINVAL)
+    }
+}
+
+// Runs in the parent process, until no process uses the seccomp filter of the container anymore
+pub fn supervise(fd: RawFd, mut handlers: Vec<Box<dyn NotifyHandler>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            if let Err(e) = poll(&mut fds, -1) {
+                if e == Errno::EINTR {
+                    continue;
+                }
+                log::error!("Cannot poll seccomp listener: {}", e);
+                break;
+            }
+            let revents = fds[0].revents().unwrap_or_else(PollFlags::empty);
+            if !revents.contains(PollFlags::POLLIN) {
+                break;
+            }
+
+            // The process may have been killed since the poll, just wait for the next one
+            let req = match ScmpNotifReq::receive(fd) {
+                Ok(req) => req,
+                Err(e) => {
+                    log::debug!("Cannot receive seccomp notification: {}", e);
+                    continue;
+                }
+            };
+            let verdict = match req.data.syscall.get_name_by_arch(req.data.arch) {
+                Ok(syscall) => {
+                    let notif = Notification {
+                        fd,
+                        req: &req,
+                        syscall: &syscall,
+                    };
+                    match handlers.iter_mut().find(|h| h.handles(&syscall)) {
+                        Some(handler) => handler.handle(&notif),
+                        None => {
+                            log::error!("No handler for intercepted syscall {}", syscall);
+                            Verdict::Deny(libc::ENOSYS)
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Unknown syscall in seccomp notification: {}", e);
+                    Verdict::Deny(libc::ENOSYS)
+                }
+            };
+            log::debug!("Seccomp notification {:?}: {:?}", req.data.syscall, verdict);
+
+            let resp = match verdict {
+                Verdict::Continue => {
+                    ScmpNotifResp::new_continue(req.id, ScmpNotifRespFlags::empty())
+                }
+                Verdict::Deny(errno) => {
+                    ScmpNotifResp::new_error(req.id, -errno, ScmpNotifRespFlags::empty())
+                }
+                Verdict::Return(val) => {
+                    ScmpNotifResp::new_val(req.id, val, ScmpNotifRespFlags::empty())
+                }
+            };
+            if let Err(e) = resp.respond(fd) {
+                log::debug!("Cannot respond to seccomp notification: {}", e);
+            }
+        }
+
+        if let Err(e) = close(fd) {
+            log::error!("Unable to close seccomp listener: {:?}", e);
+        }
+    })
+}
+
+// Records every syscall to build a profile, see syscalls::learned_profile
+pub struct LearnHandler {
+    pub seen: LearnedSyscalls,
+    pub args: &'static [(&'static str, u32)],
+}
+
+impl NotifyHandler for LearnHandler {
+    fn handles(&self, _syscall: &str) -> bool {
+        true
+    }
+
+    fn handle(&mut self, notif: &Notification) -> Verdict {
+        if let Ok(mut seen) = self.seen.lock() {
+            let values = seen.entry(notif.syscall.to_string()).or_default();
+            if let Some((_, ind)) = self.args.iter().find(|(sc, _)| *sc == notif.syscall) {
+                values.insert(notif.arg(*ind as usize));
+            }
+        }
+        Verdict::Continue
+    }
+}
+
+// One line of the log of denied syscalls, in JSON
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeniedSyscall {
+    pub time: String,
+    pub pid: u32,
+    pub syscall: String,
+    pub args: [u64; 6],
+    pub errno: i32,
+}
+
+// Any syscall notified without being intercepted was refused by the seccomp profile:
+// it is logged, then fails with the errno the profile gives it
+pub struct DenyLogHandler {
+    pub errnos: HashMap<String, i32>,
+    pub default_errno: i32,
+    pub log: File,
+}
+
+impl NotifyHandler for DenyLogHandler {
+    fn handles(&self, _syscall: &str) -> bool {
+        true
+    }
+
+    fn handle(&mut self, notif: &Notification) -> Verdict {
+        let errno = match self.errnos.get(notif.syscall) {
+            Some(errno) => *errno,
+            None => self.default_errno,
+        };
+        let denied = DeniedSyscall {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            pid: notif.req.pid,
+            syscall: notif.syscall.to_string(),
+            args: notif.req.data.args,
+            errno,
+        };
+        log::warn!(
+            "Denied syscall {} (args {:x?}) to pid {}",
+            denied.syscall,
+            denied.args,
+            denied.pid
+        );
+        match serde_json::to_string(&denied) {
+            Ok(line) => {
+                if let Err(e) = writeln!(self.log, "{}", line) {
+                    log::error!("Cannot write to log of denied syscalls: {}", e);
+                }
+            }
+            Err(e) => log::error!("Cannot serialize denied syscall: {}", e),
+        }
+        Verdict::Deny(errno)
+    }
+}
+
+// The container has no CAP_MKNOD, the supervisor creates the device nodes that are allowed
+pub struct MknodHandler;
+
+impl NotifyHandler for MknodHandler {
+    fn handles(&self, syscall: &str) -> bool {
+        Intercept::Mknod.syscalls().contains(&syscall)
+    }
+
+    fn handle(&mut self, notif: &Notification) -> Verdict {
+        // mknodat(dirfd, path, mode, dev) has the arguments of mknod(path, mode, dev) shifted by one
+        let (dirfd, first) = match notif.syscall {
+            "mknodat" => (Some(notif.arg(0) as i32), 1),
+            _ => (None, 0),
+        };
+        let path = match notif.read_string(notif.arg(first)) {
+            Ok(path) => path,
+            Err(errno) => return Verdict::Deny(errno),
+        };
+        let mode = notif.arg(first + 1) as libc::mode_t;
+        let dev = notif.arg(first + 2) as libc::dev_t;
+
+        let kind = SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits());
+        if kind != SFlag::S_IFCHR || !MKNOD_ALLOWED.contains(&(major(dev), minor(dev))) {
+            log::info!(
+                "Refusing mknod of {:?} ({}:{}) in container",
+                path,
+                major(dev),
+                minor(dev)
+            );
+            return Verdict::Deny(libc::EPERM);
+        }
+
+        // The node belongs to whoever asked for it, not to the root user of the host
+        let (uid, gid) = match caller_ids(notif.req.pid) {
+            Some(ids) => ids,
+            None => return Verdict::Deny(libc::ESRCH),
+        };
+        let perm = Mode::from_bits_truncate(mode & !SFlag::S_IFMT.bits());
+        let res = in_container_mount_ns(notif.req.pid, dirfd, || {
+            mknod(path.as_c_str(), kind, perm, dev)?;
+            chown(path.as_c_str(), Some(uid), Some(gid))
+        });
+        match res {
+            Ok(_) => {
+                log::info!("Created device {:?} in container", path);
+                Verdict::Return(0)
+            }
+            Err(errno) => Verdict::Deny(errno as i32),
+        }
+    }
+}
+
+// Mounts the few filesystems that are harmless for the host on behalf of the container
+pub struct MountHandler;
+
+impl NotifyHandler for MountHandler {
+    fn handles(&self, syscall: &str) -> bool {
+        Intercept::Mount.syscalls().contains(&syscall)
+    }
+
+    fn handle(&mut self, notif: &Notification) -> Verdict {
+        let mut strings = Vec::new();
+        for ind in [0, 1, 2] {
+            match notif.read_string(notif.arg(ind)) {
+                Ok(s) => strings.push(s),
+                Err(errno) => return Verdict::Deny(errno),
+            }
+        }
+        let data = match notif.arg(4) {
+            0 => None,
+            addr => match notif.read_string(addr) {
+                Ok(s) => Some(s),
+                Err(errno) => return Verdict::Deny(errno),
+            },
+        };
+        let (fstype, target, source) = (strings.pop(), strings.pop(), strings.pop());
+        let (fstype, target, source) = match (fstype, target, source) {
+            (Some(f), Some(t), Some(s)) => (f, t, s),
+            _ => return Verdict::Deny(libc::EFAULT),
+        };
+        let flags = MsFlags::from_bits_truncate(notif.arg(3));
+
+        let allowed = MOUNT_ALLOWED_FS
+            .iter()
+            .find(|(fs, _)| fs.as_bytes() == fstype.as_bytes())
+            .map(|(_, options)| allowed_options(options, data.as_deref()));
+        if allowed != Some(true) || !MOUNT_ALLOWED_FLAGS.contains(flags) {
+            log::info!(
+                "Refusing mount of {:?} on {:?} in container",
+                fstype,
+                target
+            );
+            return Verdict::Deny(libc::EPERM);
+        }
+
+        let res = in_container_mount_ns(notif.req.pid, None, || {
+            mount(
+                Some(source.as_c_str()),
+                target.as_c_str(),
+                Some(fstype.as_c_str()),
+                flags,
+                data.as_deref(),
+            )
+        });
+        match res {
+            Ok(_) => {
+                log::info!("Mounted {:?} on {:?} in container", fstype, target);
+                Verdict::Return(0)
+            }
+            Err(errno) => Verdict::Deny(errno as i32),
+        }
+    }
+}
+
+// Every option of the mount data has to be allowed, alone or with a mode in octal
+fn allowed_options(allowed: &[&str], data: Option<&CStr>) -> bool {
+    let data = match data.map(|d| d.to_str()) {
+        None => return true,
+        Some(Ok(data)) => data,
+        Some(Err(_)) => return false,
+    };
+    data.split(',')
+        .filter(|option| !option.is_empty())
+        .all(|option| {
+            let (key, mode) = match option.split_once('=') {
+                Some((key, mode)) => (key, Some(mode)),
+                None => (option, None),
+            };
+            let valid = match mode {
+                Some(mode) => matches!(u32::from_str_radix(mode, 8), Ok(mode) if mode <= 0o7777),
+                None => true,
+            };
+            allowed.contains(&key) && valid
+        })
+}
+
+// The container has its own UTS namespace, the new hostname is only logged and approved
+pub struct SethostnameHandler;
+
+impl NotifyHandler for SethostnameHandler {
+    fn handles(&self, syscall: &str) -> bool {
+        Intercept::Sethostname.syscalls().contains(&syscall)
+    }
+
+    fn handle(&mut self, notif: &Notification) -> Verdict {
+        match notif.read_string(notif.arg(0)) {
+            Ok(name) => {
+                log::info!("Container sets its hostname to {:?}", name);
+                Verdict::Continue
+            }
+            Err(errno) => Verdict::Deny(errno),
+        }
+    }
+}
+
+// Filesystem uid and gid of the process, as seen from the host
+fn caller_ids(pid: u32) -> Option<(Uid, Gid)> {
+    let status = read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let field = |name: &str| -> Option<u32> {
+        let line = status.lines().find(|l| l.starts_with(name))?;
+        // Real, effective, saved set and filesystem IDs
+        line.split_whitespace().nth(4)?.parse().ok()
+    };
+    Some((Uid::from_raw(field("Uid:")?), Gid::from_raw(field("Gid:")?)))
+}
+
+// Runs f in a child process that joined the mount, network and IPC namespaces of the container
+// process pid. Relative paths are resolved from dirfd of the process, or from its working directory.
+// A process using threads can't change its mount namespace, hence the fork.
+fn in_container_mount_ns<F>(pid: u32, dirfd: Option<i32>, f: F) -> Result<(), Errno>
+where
+    F: FnOnce() -> Result<(), Errno>,
+{
+    let cwd = match dirfd {
+        Some(fd) if fd != libc::AT_FDCWD => format!("/proc/{}/fd/{}", pid, fd),
+        _ => format!("/proc/{}/cwd", pid),
+    };
+    let mut fds = Vec::new();
+    for path in [
+        format!("/proc/{}/ns/net", pid),
+        format!("/proc/{}/ns/ipc", pid),
+        format!("/proc/{}/ns/mnt", pid),
+        cwd,
+    ] {
+        match open(
+            path.as_str(),
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(fd) => fds.push(fd),
+            Err(e) => {
+                close_all(&fds);
+                return Err(e);
+            }
+        }
+    }
+
+    let forked = spawn_helper(
+        || unsafe { fork() },
+        |forked| match forked {
+            ForkResult::Parent { child } => Some(*child),
+            ForkResult::Child => None,
+        },
+    );
+    let res = match forked {
+        Ok(ForkResult::Child) => {
+            let res = setns(fds[0], CloneFlags::CLONE_NEWNET)
+                .and_then(|_| setns(fds[1], CloneFlags::CLONE_NEWIPC))
+                .and_then(|_| setns(fds[2], CloneFlags::CLONE_NEWNS))
+                .and_then(|_| fchdir(fds[3]))
+                .and_then(|_| f());
+            let code = match res {
+                Ok(_) => 0,
+                Err(errno) => errno as i32,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let status = waitpid(child, None);
+            helper_waited(child);
+            match status {
+                Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+                Ok(WaitStatus::Exited(_, code)) => Err(Errno::from_i32(code)),
+                _ => Err(Errno::EIO),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    close_all(&fds);
+    res
+}
+
+fn close_all(fds: &[RawFd]) {
+    for fd in fds {
+        if let Err(e) = close(*fd) {
+            log::error!("Unable to close fd {}: {:?}", fd, e);
+        }
+    }
+}
@@ -4,83 +4,749 @@
 // Should it attempt any other system calls, the kernel will terminate the process with SIGKILL or SIGSYS.
 // In this sense, it does not virtualize the system’s resources but isolates the process from them entirely.
 use crate::errors::Errcode;
+use crate::supervisor::Intercept;
 use libc::TIOCSTI;
+use libseccomp::{
+    ScmpAction, ScmpArch, ScmpArgCompare, ScmpCompareOp, ScmpFilterContext, ScmpSyscall,
+};
 use nix::sched::CloneFlags;
 use nix::sys::stat::Mode;
-use syscallz::{Action, Cmp, Comparator, Context, Syscall};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-const EPERM: u16 = 1;
+const EPERM: i32 = 1;
+const ENOSYS: i32 = 38;
 
-pub fn setsyscalls() -> Result<(), Errcode> {
+// The flags of clone are its second argument on s390, and the first one everywhere else
+#[cfg(target_arch = "s390x")]
+const CLONE_FLAGS_ARG: u32 = 1;
+#[cfg(not(target_arch = "s390x"))]
+const CLONE_FLAGS_ARG: u32 = 0;
+
+// The ioctl request is an unsigned int, the upper half of the register is ignored by the kernel
+const IOCTL_REQUEST_MASK: u64 = 0xFFFF_FFFF;
+
+// The kernel also accepts syscalls made through the ABIs of these architectures
+// (e.g. int 0x80 on x86_64), the rules are added for each of them so they can't be bypassed.
+// Syscalls from any other architecture kill the process.
+#[cfg(target_arch = "x86_64")]
+const COMPAT_ARCHS: [ScmpArch; 2] = [ScmpArch::X86, ScmpArch::X32];
+#[cfg(target_arch = "aarch64")]
+const COMPAT_ARCHS: [ScmpArch; 1] = [ScmpArch::Arm];
+#[cfg(target_arch = "s390x")]
+const COMPAT_ARCHS: [ScmpArch; 1] = [ScmpArch::S390];
+// libseccomp can't mix endianness in a filter, and 32-bit PowerPC is big-endian only
+#[cfg(all(target_arch = "powerpc64", target_endian = "big"))]
+const COMPAT_ARCHS: [ScmpArch; 1] = [ScmpArch::Ppc];
+#[cfg(all(target_arch = "powerpc64", target_endian = "little"))]
+const COMPAT_ARCHS: [ScmpArch; 0] = [];
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "s390x",
+    target_arch = "powerpc64"
+)))]
+const COMPAT_ARCHS: [ScmpArch; 0] = [];
+
+// While learning, the value of these arguments is recorded as well as the syscall itself,
+// so the generated profile only allows e.g. the socket families the workload really used
+pub const LEARN_ARGS: [(&str, u32); 2] = [("socket", 0), ("personality", 0)];
+
+// The listener fd is handed to the parent with sendmsg once the learning filter is loaded.
+// Until the parent receives it, no one answers the notifications: the memory the child frees
+// or allocates to build the message, and the descriptors it closes, can't go through the
+// filter either. These syscalls are always part of a learned profile.
+const LEARN_ALWAYS_ALLOWED: [&str; 8] = [
+    "sendmsg", "brk", "mmap", "munmap", "mremap", "madvise", "mprotect", "close",
+];
+
+// The filter of the container is saved in its state directory, crabcan exec applies it
+// to the processes it runs
+pub const SECCOMP_PROFILE: &str = "seccomp.json";
+
+// Syscall names seen while learning, with the recorded argument values
+pub type LearnedSyscalls = Arc<Mutex<BTreeMap<String, BTreeSet<u64>>>>;
+
+// A seccomp profile as stored on disk, in JSON:
+// {
+//   "default_action": "errno",
+//   "syscalls": [
+//     { "names": ["read", "write"], "action": "allow" },
+//     { "names": ["socket"], "action": "allow", "args": [{ "op": "any_of", "index": 0, "values": [1, 2] }] },
+//     { "names": ["clone3"], "action": "errno", "errno": 38 }
+//   ]
+// }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeccompProfile {
+    pub default_action: ProfileAction,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileAction {
+    Allow,
+    Errno,
+    Log,
+    KillProcess,
+}
+
+impl From<ProfileAction> for ScmpAction {
+    fn from(action: ProfileAction) -> ScmpAction {
+        match action {
+            ProfileAction::Allow => ScmpAction::Allow,
+            ProfileAction::Errno => ScmpAction::Errno(EPERM),
+            ProfileAction::Log => ScmpAction::Log,
+            ProfileAction::KillProcess => ScmpAction::KillProcess,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyscallRule {
+    pub names: Vec<String>,
+    pub action: ProfileAction,
+    // Errno returned with the errno action, EPERM if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+    // All the comparisons have to match for the action to be taken
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<ArgRule>,
+}
+
+impl SyscallRule {
+    fn new(names: &[&str], action: ProfileAction, args: Vec<ArgRule>) -> SyscallRule {
+        SyscallRule {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            action,
+            errno: None,
+            args,
+        }
+    }
+
+    fn scmp_action(&self) -> ScmpAction {
+        match (self.action, self.errno) {
+            (ProfileAction::Errno, Some(errno)) => ScmpAction::Errno(errno),
+            (action, _) => action.into(),
+        }
+    }
+}
+
+// A comparison on the argument number index of the syscall
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ArgRule {
+    // The argument is exactly value
+    Eq { index: u32, value: u64 },
+    // The argument, with only the bits of mask kept, is value.
+    // With the same value for mask and value, this tests if the bits of value are set
+    MaskedEq { index: u32, mask: u64, value: u64 },
+    // The argument is between min and max, both included
+    Range { index: u32, min: u64, max: u64 },
+    // The argument is one of the values
+    AnyOf { index: u32, values: Vec<u64> },
+}
+
+impl ArgRule {
+    // seccomp accepts a single comparison per argument in a rule, so a range or a list of
+    // values is turned into alternative comparisons, each one becoming a rule of its own
+    fn comparators(&self) -> Result<Vec<ScmpArgCompare>, Errcode> {
+        match self {
+            ArgRule::Eq { index, value } => Ok(vec![ScmpArgCompare::new(
+                *index,
+                ScmpCompareOp::Equal,
+                *value,
+            )]),
+            ArgRule::MaskedEq { index, mask, value } => Ok(vec![ScmpArgCompare::new(
+                *index,
+                ScmpCompareOp::MaskedEqual(*mask),
+                *value,
+            )]),
+            ArgRule::Range { index, min, max } => {
+                if min > max {
+                    log::error!("Invalid range {}-{} for argument {}", min, max, index);
+                    return Err(Errcode::SyscallsError(10));
+                }
+                Ok(range_blocks(*min, *max)
+                    .into_iter()
+                    .map(|(mask, value)| {
+                        ScmpArgCompare::new(*index, ScmpCompareOp::MaskedEqual(mask), value)
+                    })
+                    .collect())
+            }
+            ArgRule::AnyOf { index, values } => {
+                if values.is_empty() {
+                    log::error!("No value to compare argument {} to", index);
+                    return Err(Errcode::SyscallsError(10));
+                }
+                Ok(values
+                    .iter()
+                    .map(|value| ScmpArgCompare::new(*index, ScmpCompareOp::Equal, *value))
+                    .collect())
+            }
+        }
+    }
+}
+
+// Splits [min, max] into aligned blocks of 2^n values, each one matched by a masked equality
+// on its upper bits. E.g. 5-8 gives 5 (mask !0), 6-7 (mask !1) and 8 (mask !0)
+fn range_blocks(min: u64, max: u64) -> Vec<(u64, u64)> {
+    let mut blocks = Vec::new();
+    let (mut start, end) = (min as u128, max as u128 + 1);
+    while start < end {
+        let mut bits = if start == 0 {
+            64
+        } else {
+            start.trailing_zeros()
+        };
+        while start + (1u128 << bits) > end {
+            bits -= 1;
+        }
+        blocks.push((!(((1u128 << bits) - 1) as u64), start as u64));
+        start += 1u128 << bits;
+    }
+    blocks
+}
+
+// Every combination of the alternatives of each argument, see ArgRule::comparators
+fn comparator_sets(args: &[ArgRule]) -> Result<Vec<Vec<ScmpArgCompare>>, Errcode> {
+    let mut sets = vec![Vec::new()];
+    for arg in args.iter() {
+        let alternatives = arg.comparators()?;
+        sets = sets
+            .iter()
+            .flat_map(|set| {
+                alternatives.iter().map(move |cmp| {
+                    let mut set = set.clone();
+                    set.push(*cmp);
+                    set
+                })
+            })
+            .collect();
+    }
+    Ok(sets)
+}
+
+impl SeccompProfile {
+    pub fn from_file(path: &PathBuf) -> Result<SeccompProfile, Errcode> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("Cannot open seccomp profile {}: {}", path.display(), e);
+                return Err(Errcode::ArgumentInvalid("seccomp-profile"));
+            }
+        };
+        match serde_json::from_reader(file) {
+            Ok(profile) => Ok(profile),
+            Err(e) => {
+                log::error!("Cannot parse seccomp profile {}: {}", path.display(), e);
+                Err(Errcode::ArgumentInvalid("seccomp-profile"))
+            }
+        }
+    }
+
+    // Errno returned by the syscalls refused with the errno action.
+    // With several rules for a syscall, the first one wins.
+    pub fn errnos(&self) -> HashMap<String, i32> {
+        let mut errnos = HashMap::new();
+        for rule in self.syscalls.iter() {
+            if let ScmpAction::Errno(errno) = rule.scmp_action() {
+                for name in rule.names.iter() {
+                    errnos.entry(name.clone()).or_insert(errno);
+                }
+            }
+        }
+        errnos
+    }
+
+    // Errno of the syscalls refused by the default action of the profile
+    pub fn default_errno(&self) -> i32 {
+        match self.default_action.into() {
+            ScmpAction::Errno(errno) => errno,
+            _ => EPERM,
+        }
+    }
+
+    pub fn to_file(&self, path: &PathBuf) -> Result<(), Errcode> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("Cannot create seccomp profile {}: {}", path.display(), e);
+                return Err(Errcode::SyscallsError(5));
+            }
+        };
+        if let Err(e) = serde_json::to_writer_pretty(file, self) {
+            log::error!("Cannot write seccomp profile {}: {}", path.display(), e);
+            return Err(Errcode::SyscallsError(5));
+        }
+        Ok(())
+    }
+}
+
+// How the seccomp filter of the container is built
+#[derive(Debug, Clone)]
+pub enum SeccompMode {
+    // The hardcoded deny rules below
+    Default,
+    // A profile loaded from a JSON file
+    Profile(SeccompProfile),
+    // Every syscall is reported to the parent process, which records it and lets it through
+    Learn,
+}
+
+impl SeccompMode {
+    // The profile the filter is built from, there is none while learning
+    pub fn profile(&self) -> Option<SeccompProfile> {
+        match self {
+            SeccompMode::Default => Some(default_profile()),
+            SeccompMode::Profile(profile) => Some(profile.clone()),
+            SeccompMode::Learn => None,
+        }
+    }
+}
+
+// Everything needed to build the seccomp filter of the container
+#[derive(Debug, Clone)]
+pub struct SeccompConfig {
+    pub mode: SeccompMode,
+    // Syscalls handled by the supervisor in the parent process
+    pub intercept: Vec<Intercept>,
+    // Syscalls refused with an errno are reported to the supervisor, which logs them
+    pub log_denied: bool,
+}
+
+impl SeccompConfig {
+    // Tells if the parent process has to supervise the seccomp notifications of the container
+    pub fn needs_supervisor(&self) -> bool {
+        matches!(self.mode, SeccompMode::Learn) || !self.intercept.is_empty() || self.log_denied
+    }
+}
+
+// Loads the seccomp filter, returns the notification listener fd when the parent has to receive it
+pub fn setsyscalls(config: &SeccompConfig) -> Result<Option<RawFd>, Errcode> {
+    let intercepted: Vec<&str> = config
+        .intercept
+        .iter()
+        .flat_map(|i| i.syscalls())
+        .copied()
+        .collect();
+    let mut ctx = match config.mode.profile() {
+        Some(profile) => profile_filter(&profile, &intercepted, config.log_denied)?,
+        None => learn_filter()?,
+    };
+
+    // In learning mode, every syscall is already notified
+    if !matches!(config.mode, SeccompMode::Learn) {
+        for name in intercepted.iter() {
+            if let Err(e) = ctx.add_rule(ScmpAction::Notify, syscall_from_name(name)?) {
+                log::error!("Cannot intercept syscall {}: {}", name, e);
+                return Err(Errcode::SyscallsError(2));
+            }
+        }
+    }
+
+    if let Err(e) = ctx.load() {
+        log::error!("Cannot load seccomp filter: {}", e);
+        return Err(Errcode::SyscallsError(0));
+    }
+
+    if !config.needs_supervisor() {
+        return Ok(None);
+    }
+    match ctx.get_notify_fd() {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) => {
+            log::error!("Cannot get seccomp notification fd: {}", e);
+            Err(Errcode::SyscallsError(6))
+        }
+    }
+}
+
+pub fn default_profile() -> SeccompProfile {
     let s_isuid: u64 = Mode::S_ISUID.bits().into();
     let s_isgid: u64 = Mode::S_ISGID.bits().into();
     let clone_new_user: u64 = CloneFlags::CLONE_NEWUSER.bits() as u64;
+    let set_bit = |index: u32, bit: u64| {
+        vec![ArgRule::MaskedEq {
+            index,
+            mask: bit,
+            value: bit,
+        }]
+    };
 
     // Unconditionnal syscall deny
-    let syscalls_refused = [
-        Syscall::keyctl,
-        Syscall::add_key,
-        Syscall::request_key,
-        Syscall::mbind,
-        Syscall::migrate_pages,
-        Syscall::move_pages,
-        Syscall::set_mempolicy,
-        Syscall::userfaultfd,
-        Syscall::perf_event_open,
-    ];
+    let mut syscalls = vec![SyscallRule::new(
+        &[
+            "keyctl",
+            "add_key",
+            "request_key",
+            "mbind",
+            "migrate_pages",
+            "move_pages",
+            "set_mempolicy",
+            "userfaultfd",
+            "perf_event_open",
+        ],
+        ProfileAction::Errno,
+        Vec::new(),
+    )];
 
     // Conditionnal syscall deny
-    let syscalls_refuse_ifcomp = [
-        (Syscall::chmod, 1, s_isuid),
-        (Syscall::chmod, 1, s_isgid),
-        (Syscall::fchmod, 1, s_isuid),
-        (Syscall::fchmod, 1, s_isgid),
-        (Syscall::fchmodat, 2, s_isuid),
-        (Syscall::fchmodat, 2, s_isgid),
-        (Syscall::unshare, 0, clone_new_user),
-        (Syscall::clone, 0, clone_new_user),
-        (Syscall::ioctl, 1, TIOCSTI),
-    ];
+    for (names, args) in [
+        (&["chmod", "fchmod"][..], set_bit(1, s_isuid)),
+        (&["chmod", "fchmod"][..], set_bit(1, s_isgid)),
+        (&["fchmodat"][..], set_bit(2, s_isuid)),
+        (&["fchmodat"][..], set_bit(2, s_isgid)),
+        (&["unshare"][..], set_bit(0, clone_new_user)),
+        (&["clone"][..], set_bit(CLONE_FLAGS_ARG, clone_new_user)),
+        (
+            &["ioctl"][..],
+            vec![ArgRule::MaskedEq {
+                index: 1,
+                mask: IOCTL_REQUEST_MASK,
+                value: TIOCSTI,
+            }],
+        ),
+    ] {
+        syscalls.push(SyscallRule::new(names, ProfileAction::Errno, args));
+    }
 
-    // Initialize seccomp profile with all syscalls allowed by default
-    if let Ok(mut ctx) = Context::init_with_action(Action::Allow) {
-        // Configure profile here
-        for sc in syscalls_refused.iter() {
-            refuse_syscall(&mut ctx, sc)?;
+    // The flags of clone3 are in a struct seccomp can't look into, making it fail with
+    // ENOSYS makes the libc fall back to clone, whose flags are checked above
+    let mut clone3 = SyscallRule::new(&["clone3"], ProfileAction::Errno, Vec::new());
+    clone3.errno = Some(ENOSYS);
+    syscalls.push(clone3);
+
+    // All syscalls allowed by default
+    SeccompProfile {
+        default_action: ProfileAction::Allow,
+        syscalls,
+    }
+}
+
+// The rules of the profile for intercepted syscalls are replaced by the notification.
+// When denied syscalls are logged, they are notified instead of failing right away.
+fn profile_filter(
+    profile: &SeccompProfile,
+    intercepted: &[&str],
+    log_denied: bool,
+) -> Result<ScmpFilterContext, Errcode> {
+    let notify_denied = |action: ScmpAction| match action {
+        ScmpAction::Errno(_) if log_denied => ScmpAction::Notify,
+        _ => action,
+    };
+    let mut ctx = new_filter(notify_denied(profile.default_action.into()))?;
+    for rule in profile.syscalls.iter() {
+        let sets = comparator_sets(&rule.args)?;
+        for name in rule.names.iter() {
+            if intercepted.contains(&name.as_str()) {
+                continue;
+            }
+            let sc = syscall_from_name(name)?;
+            for comparators in sets.iter() {
+                add_rule(
+                    &mut ctx,
+                    notify_denied(rule.scmp_action()),
+                    name,
+                    sc,
+                    comparators,
+                )?;
+            }
         }
+    }
+    Ok(ctx)
+}
 
-        for (sc, ind, biteq) in syscalls_refuse_ifcomp.iter() {
-            refuse_if_comp(&mut ctx, *ind, sc, *biteq)?;
+fn learn_filter() -> Result<ScmpFilterContext, Errcode> {
+    let mut ctx = new_filter(ScmpAction::Notify)?;
+    for name in LEARN_ALWAYS_ALLOWED.iter() {
+        if let Err(e) = ctx.add_rule(ScmpAction::Allow, syscall_from_name(name)?) {
+            log::error!("Cannot allow {} in learning filter: {}", name, e);
+            return Err(Errcode::SyscallsError(2));
         }
+    }
+    Ok(ctx)
+}
 
-        if let Err(_) = ctx.load() {
-            return Err(Errcode::SyscallsError(0));
+// The architectures have to be added before any rule, so the rules apply to all of them
+fn new_filter(default_action: ScmpAction) -> Result<ScmpFilterContext, Errcode> {
+    let mut ctx = match ScmpFilterContext::new_filter(default_action) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            log::error!("Cannot initialize seccomp filter: {}", e);
+            return Err(Errcode::SyscallsError(1));
         }
+    };
 
-        Ok(())
-    } else {
-        Err(Errcode::SyscallsError(1))
+    for arch in COMPAT_ARCHS.iter() {
+        if let Err(e) = ctx.add_arch(*arch) {
+            log::error!(
+                "Cannot add architecture {:?} to seccomp filter: {}",
+                arch,
+                e
+            );
+            return Err(Errcode::SyscallsError(9));
+        }
+    }
+    if let Err(e) = ctx.set_act_badarch(ScmpAction::KillProcess) {
+        log::error!("Cannot set seccomp action for other architectures: {}", e);
+        return Err(Errcode::SyscallsError(9));
     }
+    Ok(ctx)
 }
 
-//this would refuse the call to certain sys calls
-fn refuse_syscall(ctx: &mut Context, sc: &Syscall) -> Result<(), Errcode> {
-    match ctx.set_action_for_syscall(Action::Errno(EPERM), *sc) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(Errcode::SyscallsError(2)),
+fn syscall_from_name(name: &str) -> Result<ScmpSyscall, Errcode> {
+    match ScmpSyscall::from_name(name) {
+        Ok(sc) => Ok(sc),
+        Err(_) => {
+            log::error!("Unknown syscall {}", name);
+            Err(Errcode::SyscallsError(4))
+        }
     }
 }
 
-//Syscalls can be restricted when a particular condition is met
-//What this Comparator will do is to take the argument number ind passed to the syscall, and compare it using the mask biteq to the value biteq.
-//This is equivalent to testing if the bit biteq is set.
-fn refuse_if_comp(ctx: &mut Context, ind: u32, sc: &Syscall, biteq: u64) -> Result<(), Errcode> {
-    match ctx.set_rule_for_syscall(
-        Action::Errno(EPERM),
-        *sc,
-        &[Comparator::new(ind, Cmp::MaskedEq, biteq, Some(biteq))],
-    ) {
+//Syscalls can be restricted when a particular condition is met on their arguments,
+//the action is taken only if all the comparators match
+fn add_rule(
+    ctx: &mut ScmpFilterContext,
+    action: ScmpAction,
+    name: &str,
+    sc: ScmpSyscall,
+    comparators: &[ScmpArgCompare],
+) -> Result<(), Errcode> {
+    let res = if comparators.is_empty() {
+        ctx.add_rule(action, sc)
+    } else {
+        ctx.add_rule_conditional(action, sc, comparators)
+    };
+    match res {
         Ok(_) => Ok(()),
-        Err(_) => Err(Errcode::SyscallsError(3)),
+        Err(e) => {
+            log::error!("Cannot add seccomp rule for {}: {}", name, e);
+            Err(Errcode::SyscallsError(3))
+        }
+    }
+}
+
+// Turns the syscalls recorded while the container ran into an allowlist profile
+pub fn learned_profile(seen: &LearnedSyscalls) -> SeccompProfile {
+    let mut seen = match seen.lock() {
+        Ok(seen) => seen.clone(),
+        Err(_) => BTreeMap::new(),
+    };
+    for name in LEARN_ALWAYS_ALLOWED.iter() {
+        seen.entry(name.to_string()).or_default();
+    }
+
+    let mut names = Vec::new();
+    let mut syscalls = Vec::new();
+    for (name, values) in seen.into_iter() {
+        match LEARN_ARGS.iter().find(|(sc, _)| *sc == name) {
+            Some((_, ind)) => syscalls.push(SyscallRule {
+                names: vec![name],
+                action: ProfileAction::Allow,
+                errno: None,
+                args: vec![ArgRule::AnyOf {
+                    index: *ind,
+                    values: values.into_iter().collect(),
+                }],
+            }),
+            None => names.push(name),
+        }
+    }
+    syscalls.insert(
+        0,
+        SyscallRule {
+            names,
+            action: ProfileAction::Allow,
+            errno: None,
+            args: Vec::new(),
+        },
+    );
+
+    SeccompProfile {
+        default_action: ProfileAction::Errno,
+        syscalls,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::errno::Errno;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use std::ffi::CString;
+
+    fn masked(index: u32, mask: u64, value: u64) -> ScmpArgCompare {
+        ScmpArgCompare::new(index, ScmpCompareOp::MaskedEqual(mask), value)
+    }
+
+    fn equal(index: u32, value: u64) -> ScmpArgCompare {
+        ScmpArgCompare::new(index, ScmpCompareOp::Equal, value)
+    }
+
+    #[test]
+    fn range_blocks_split_on_alignment() {
+        assert_eq!(range_blocks(5, 8), vec![(!0, 5), (!1, 6), (!0, 8)]);
+        assert_eq!(range_blocks(7, 7), vec![(!0, 7)]);
+        assert_eq!(range_blocks(0, 3), vec![(!3, 0)]);
+        assert_eq!(range_blocks(4, 11), vec![(!3, 4), (!3, 8)]);
+    }
+
+    #[test]
+    fn range_blocks_at_the_edges() {
+        assert_eq!(range_blocks(0, u64::MAX), vec![(0, 0)]);
+        assert_eq!(range_blocks(u64::MAX, u64::MAX), vec![(!0, u64::MAX)]);
+        assert_eq!(
+            range_blocks(u64::MAX - 1, u64::MAX),
+            vec![(!1, u64::MAX - 1)]
+        );
+        assert_eq!(range_blocks(1, u64::MAX).len(), 64);
+    }
+
+    #[test]
+    fn comparator_sets_expand_each_argument() {
+        let args = vec![
+            ArgRule::MaskedEq {
+                index: 0,
+                mask: 0o4000,
+                value: 0o4000,
+            },
+            ArgRule::AnyOf {
+                index: 1,
+                values: vec![1, 2],
+            },
+            ArgRule::Range {
+                index: 2,
+                min: 6,
+                max: 8,
+            },
+        ];
+        let sets = comparator_sets(&args).unwrap();
+        let setuid = masked(0, 0o4000, 0o4000);
+        assert_eq!(
+            sets,
+            vec![
+                vec![setuid, equal(1, 1), masked(2, !1, 6)],
+                vec![setuid, equal(1, 1), masked(2, !0, 8)],
+                vec![setuid, equal(1, 2), masked(2, !1, 6)],
+                vec![setuid, equal(1, 2), masked(2, !0, 8)],
+            ]
+        );
+        assert_eq!(comparator_sets(&[]).unwrap(), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn comparator_sets_refuse_empty_comparisons() {
+        let range = ArgRule::Range {
+            index: 0,
+            min: 2,
+            max: 1,
+        };
+        assert!(comparator_sets(&[range]).is_err());
+        let any_of = ArgRule::AnyOf {
+            index: 0,
+            values: Vec::new(),
+        };
+        assert!(comparator_sets(&[any_of]).is_err());
+    }
+
+    // Runs f in a child process with the default profile loaded, and returns its exit code.
+    // The filter is built before the fork, the child only loads it.
+    fn with_default_profile<F: FnOnce() -> i32>(f: F) -> i32 {
+        let ctx = profile_filter(&default_profile(), &[], false).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = match ctx.load() {
+                    Ok(_) => f(),
+                    Err(_) => 255,
+                };
+                unsafe { libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+                WaitStatus::Exited(_, code) => code,
+                status => panic!("Filtered child ended with {:?}", status),
+            },
+        }
+    }
+
+    // The errno of a raw syscall, 0 if it succeeded
+    fn errno(res: libc::c_long) -> i32 {
+        match res {
+            -1 => Errno::last() as i32,
+            _ => 0,
+        }
+    }
+
+    // Like fork, the child exits right away
+    fn raw_clone(flags: u64) -> i32 {
+        let res =
+            unsafe { libc::syscall(libc::SYS_clone, flags | libc::SIGCHLD as u64, 0, 0, 0, 0) };
+        if res == 0 {
+            unsafe { libc::_exit(0) }
+        }
+        if res > 0 {
+            let _ = waitpid(nix::unistd::Pid::from_raw(res as i32), None);
+        }
+        errno(res)
+    }
+
+    #[test]
+    fn chmod_setuid_is_denied() {
+        let path = std::env::temp_dir().join(format!("crabcan-chmod-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let chmod = |mode: libc::mode_t| {
+            errno(unsafe { libc::syscall(libc::SYS_chmod, c_path.as_ptr(), mode) })
+        };
+        assert_eq!(with_default_profile(|| chmod(0o4755)), libc::EPERM);
+        assert_eq!(with_default_profile(|| chmod(0o755)), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ioctl_tiocsti_is_denied() {
+        let ioctl = |request: u64| {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return 254;
+            }
+            let mut arg: libc::c_int = 0;
+            errno(unsafe { libc::syscall(libc::SYS_ioctl, fds[0], request, &mut arg) })
+        };
+        // Without the filter, TIOCSTI fails on a pipe with ENOTTY
+        assert_eq!(with_default_profile(|| ioctl(TIOCSTI)), libc::EPERM);
+        assert_eq!(with_default_profile(|| ioctl(libc::FIONREAD)), 0);
+    }
+
+    #[test]
+    fn clone_new_user_is_denied() {
+        let new_user = CloneFlags::CLONE_NEWUSER.bits() as u64;
+        assert_eq!(with_default_profile(|| raw_clone(new_user)), libc::EPERM);
+        assert_eq!(with_default_profile(|| raw_clone(0)), 0);
+    }
+
+    #[test]
+    fn clone3_is_not_implemented() {
+        let clone3 = || {
+            // struct clone_args with only the exit signal set, like fork
+            let mut args = [0u64; 11];
+            args[4] = libc::SIGCHLD as u64;
+            let size = std::mem::size_of_val(&args);
+            let res = unsafe { libc::syscall(libc::SYS_clone3, args.as_mut_ptr(), size) };
+            if res == 0 {
+                unsafe { libc::_exit(0) }
+            }
+            errno(res)
+        };
+        assert_eq!(with_default_profile(clone3), libc::ENOSYS);
+        // The libc falls back to clone, which still works
+        assert_eq!(with_default_profile(|| raw_clone(0)), 0);
     }
 }
@@ -0,0 +1,242 @@
+use crate::errors::{os_error, Errcode};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::pty::{openpty, Winsize};
+use nix::sys::socket::{
+    connect, sendmsg, socket, AddressFamily, ControlMessage, MsgFlags, SockAddr, SockFlag,
+    SockType, UnixAddr,
+};
+use nix::sys::termios::{
+    cfmakeraw, tcgetattr, tcsetattr, SetArg, SpecialCharacterIndices, Termios,
+};
+use nix::sys::uio::IoVec;
+use nix::unistd::{close, dup2, isatty, read, setsid, ttyname, write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+const STDIN: RawFd = libc::STDIN_FILENO;
+const STDOUT: RawFd = libc::STDOUT_FILENO;
+const BUFFER_SIZE: usize = 4096;
+
+// The master is kept by crabcan, the slave becomes the terminal of the container
+#[derive(Debug, Clone, Copy)]
+pub struct Pty {
+    pub master: RawFd,
+    pub slave: RawFd,
+}
+
+// The terminal gets the size of the terminal crabcan runs in, if it has one
+pub fn open_pty() -> Result<Pty, Errcode> {
+    let size = window_size(STDIN);
+    let pty = match openpty(size.as_ref(), None) {
+        Ok(pty) => pty,
+        Err(e) => {
+            log::error!("Cannot allocate a pseudo-terminal: {:?}", e);
+            return Err(Errcode::TtyError(0));
+        }
+    };
+    // The container only inherits the slave
+    if let Err(e) = fcntl(pty.master, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        log::error!("Cannot set close-on-exec on the pseudo-terminal: {:?}", e);
+        return Err(Errcode::TtyError(0));
+    }
+    Ok(Pty {
+        master: pty.master,
+        slave: pty.slave,
+    })
+}
+
+// Runs in the container process: the slave becomes the controlling terminal of a new
+// session, and the standard input and outputs of the command
+pub fn attach_terminal(slave: RawFd) -> Result<(), Errcode> {
+    if let Err(e) = setsid() {
+        log::error!("Cannot create a new session: {:?}", e);
+        return Err(os_error(Errcode::TtyError(1), e));
+    }
+    if unsafe { libc::ioctl(slave, libc::TIOCSCTTY, 0) } < 0 {
+        let e = Errno::last();
+        log::error!("Cannot set the controlling terminal: {:?}", e);
+        return Err(os_error(Errcode::TtyError(1), e));
+    }
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if let Err(e) = dup2(slave, fd) {
+            log::error!("Cannot redirect fd {} to the terminal: {:?}", fd, e);
+            return Err(os_error(Errcode::TtyError(2), e));
+        }
+    }
+    if slave > libc::STDERR_FILENO {
+        if let Err(e) = close(slave) {
+            log::error!("Unable to close the terminal: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+// The OCI convention to hand the terminal over to another program: the master is sent
+// on the unix socket at path, with the name of the slave as payload
+pub fn send_console(path: &Path, pty: &Pty) -> Result<(), Errcode> {
+    let socket = match socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    ) {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("Cannot create the console socket: {:?}", e);
+            return Err(Errcode::TtyError(4));
+        }
+    };
+    let result = connect_console(socket, path, pty);
+    if let Err(e) = close(socket) {
+        log::error!("Unable to close the console socket: {:?}", e);
+    }
+    result
+}
+
+fn connect_console(socket: RawFd, path: &Path, pty: &Pty) -> Result<(), Errcode> {
+    let address = match UnixAddr::new(path) {
+        Ok(address) => SockAddr::Unix(address),
+        Err(e) => {
+            log::error!("Invalid console socket {}: {:?}", path.display(), e);
+            return Err(Errcode::TtyError(4));
+        }
+    };
+    if let Err(e) = connect(socket, &address) {
+        log::error!(
+            "Cannot connect to console socket {}: {:?}",
+            path.display(),
+            e
+        );
+        return Err(Errcode::TtyError(4));
+    }
+    let name = match ttyname(pty.slave) {
+        Ok(name) => name,
+        Err(e) => {
+            log::error!("Cannot get the name of the terminal: {:?}", e);
+            return Err(Errcode::TtyError(5));
+        }
+    };
+    let iov = [IoVec::from_slice(name.as_os_str().as_bytes())];
+    let fds = [pty.master];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+    if let Err(e) = sendmsg(socket, &iov, &cmsg, MsgFlags::empty(), None) {
+        log::error!("Cannot send the terminal to {}: {:?}", path.display(), e);
+        return Err(Errcode::TtyError(5));
+    }
+    Ok(())
+}
+
+// Gives the size of the terminal of crabcan to the terminal of the container,
+// whose processes get a SIGWINCH from the kernel
+pub fn resize(master: RawFd) {
+    if let Some(size) = window_size(STDIN) {
+        if unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &size) } < 0 {
+            log::debug!("Cannot resize the terminal: {:?}", Errno::last());
+        }
+    }
+}
+
+fn window_size(fd: RawFd) -> Option<Winsize> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+        0 => Some(size),
+        _ => None,
+    }
+}
+
+// The terminal of crabcan in raw mode, every key goes to the terminal of the container
+// which handles line editing and signals. The previous mode is restored when dropped.
+pub struct RawTerminal {
+    saved: Termios,
+}
+
+impl RawTerminal {
+    // Nothing to do when crabcan doesn't run in a terminal
+    pub fn enable() -> Result<Option<RawTerminal>, Errcode> {
+        if !isatty(STDIN).unwrap_or(false) {
+            return Ok(None);
+        }
+        let saved = match tcgetattr(STDIN) {
+            Ok(termios) => termios,
+            Err(e) => {
+                log::error!("Cannot read the mode of the terminal: {:?}", e);
+                return Err(Errcode::TtyError(3));
+            }
+        };
+        let mut raw = saved.clone();
+        cfmakeraw(&mut raw);
+        if let Err(e) = tcsetattr(STDIN, SetArg::TCSANOW, &raw) {
+            log::error!("Cannot set the terminal in raw mode: {:?}", e);
+            return Err(Errcode::TtyError(3));
+        }
+        Ok(Some(RawTerminal { saved }))
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Err(e) = tcsetattr(STDIN, SetArg::TCSANOW, &self.saved) {
+            log::error!("Cannot restore the mode of the terminal: {:?}", e);
+        }
+    }
+}
+
+// Copies the input of crabcan to the terminal of the container, and its output to crabcan.
+// Returns the thread copying the output, which ends once no process of the container has
+// the terminal open anymore.
+pub fn proxy_terminal(master: RawFd) -> JoinHandle<()> {
+    forward_input(master);
+    thread::spawn(move || {
+        copy(master, STDOUT);
+    })
+}
+
+// The end of the input is typed as the EOF character, like ctrl-d in a terminal
+pub fn forward_input(master: RawFd) {
+    thread::spawn(move || {
+        if copy(STDIN, master) {
+            if let Ok(termios) = tcgetattr(master) {
+                let eof = termios.control_chars[SpecialCharacterIndices::VEOF as usize];
+                write_all(master, &[eof]);
+            }
+        }
+    });
+}
+
+// Until the end of the input, or an error like EIO on a terminal without slave.
+// Returns true at the end of the input.
+fn copy(from: RawFd, to: RawFd) -> bool {
+    let mut buffer = [0u8; BUFFER_SIZE];
+    loop {
+        let size = match read(from, &mut buffer) {
+            Ok(0) => return true,
+            Ok(size) => size,
+            Err(Errno::EINTR) => continue,
+            Err(_) => return false,
+        };
+        if !write_all(to, &buffer[..size]) {
+            return false;
+        }
+    }
+}
+
+pub fn write_all(fd: RawFd, buffer: &[u8]) -> bool {
+    let mut written = 0;
+    while written < buffer.len() {
+        match write(fd, &buffer[written..]) {
+            Ok(n) => written += n,
+            Err(Errno::EINTR) => continue,
+            Err(_) => return false,
+        }
+    }
+    true
+}
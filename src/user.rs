@@ -0,0 +1,253 @@
+use crate::errors::Errcode;
+
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+use std::path::Path;
+
+// Used when the user has no entry in /etc/passwd
+const DEFAULT_HOME: &str = "/";
+
+// The user running the command, resolved with the files of the container rootfs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerUser {
+    pub uid: u32,
+    pub gid: u32,
+    // Supplementary groups
+    pub groups: Vec<u32>,
+    pub home: String,
+}
+
+struct PasswdEntry {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+struct GroupEntry {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+// The user is given as user[:group], where both can be names or IDs.
+// Without group, the primary group of the user is used, or a GID equal to the UID.
+pub fn resolve_user(
+    rootfs: &Path,
+    user: &str,
+    group_add: &[String],
+) -> Result<ContainerUser, Errcode> {
+    let passwd = read_passwd(rootfs);
+    let group_file = read_group(rootfs);
+
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+    let entry = match user.parse::<u32>() {
+        Ok(uid) => passwd.iter().find(|p| p.uid == uid),
+        Err(_) => passwd.iter().find(|p| p.name == user),
+    };
+    let uid = match (user.parse::<u32>(), entry) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(entry)) => entry.uid,
+        (Err(_), None) => {
+            log::error!("User {} not found in /etc/passwd of the container", user);
+            return Err(Errcode::ArgumentInvalid("user"));
+        }
+    };
+    let gid = match (group, entry) {
+        (Some(group), _) => resolve_group(&group_file, group)?,
+        (None, Some(entry)) => entry.gid,
+        (None, None) => uid,
+    };
+
+    // Like login, the user also gets the groups listing it as a member
+    let mut groups: Vec<u32> = match entry {
+        Some(entry) => group_file
+            .iter()
+            .filter(|g| g.members.contains(&entry.name))
+            .map(|g| g.gid)
+            .collect(),
+        None => Vec::new(),
+    };
+    for group in group_add.iter() {
+        groups.push(resolve_group(&group_file, group)?);
+    }
+    groups.retain(|g| *g != gid);
+    groups.sort_unstable();
+    groups.dedup();
+
+    Ok(ContainerUser {
+        uid,
+        gid,
+        groups,
+        home: match entry {
+            Some(entry) => entry.home.clone(),
+            None => DEFAULT_HOME.to_string(),
+        },
+    })
+}
+
+fn resolve_group(group_file: &[GroupEntry], group: &str) -> Result<u32, Errcode> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    match group_file.iter().find(|g| g.name == group) {
+        Some(entry) => Ok(entry.gid),
+        None => {
+            log::error!("Group {} not found in /etc/group of the container", group);
+            Err(Errcode::ArgumentInvalid("group"))
+        }
+    }
+}
+
+// name:password:uid:gid:gecos:home:shell
+fn read_passwd(rootfs: &Path) -> Vec<PasswdEntry> {
+    read_entries(rootfs, "etc/passwd", |fields| match fields[..] {
+        [name, _, uid, gid, _, home, ..] => Some(PasswdEntry {
+            name: name.to_string(),
+            uid: uid.parse().ok()?,
+            gid: gid.parse().ok()?,
+            home: home.to_string(),
+        }),
+        _ => None,
+    })
+}
+
+// name:password:gid:member,member...
+fn read_group(rootfs: &Path) -> Vec<GroupEntry> {
+    read_entries(rootfs, "etc/group", |fields| match fields[..] {
+        [name, _, gid, members] => Some(GroupEntry {
+            name: name.to_string(),
+            gid: gid.parse().ok()?,
+            members: members
+                .split(',')
+                .filter(|m| !m.is_empty())
+                .map(|m| m.to_string())
+                .collect(),
+        }),
+        _ => None,
+    })
+}
+
+// A rootfs without the file simply has no entry
+fn read_entries<T>(rootfs: &Path, file: &str, parse: impl Fn(Vec<&str>) -> Option<T>) -> Vec<T> {
+    let content = match read_to_string(rootfs.join(file)) {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| parse(line.split(':').collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+
+    const PASSWD: &str = "\
+root:x:0:0:root:/root:/bin/sh
+# comment
+daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin
+
+alice:x:1000:1000:Alice:/home/alice:/bin/sh
+bob:x:1001:100::/home/bob:/bin/sh
+broken:x:abc:1:::/bin/sh
+";
+    const GROUP: &str = "\
+root:x:0:
+users:x:100:alice
+alice:x:1000:
+wheel:x:10:alice,bob
+audio:x:29:bob,
+";
+
+    // A rootfs with the given files in etc, removed at the end of the test
+    fn rootfs(name: &str, passwd: Option<&str>, group: Option<&str>) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("crabcan-{}-{}", name, std::process::id()));
+        create_dir_all(path.join("etc")).unwrap();
+        if let Some(passwd) = passwd {
+            write(path.join("etc/passwd"), passwd).unwrap();
+        }
+        if let Some(group) = group {
+            write(path.join("etc/group"), group).unwrap();
+        }
+        path
+    }
+
+    fn groups(group_add: &[&str]) -> Vec<String> {
+        group_add.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn users_resolve_by_name_or_id() {
+        let root = rootfs("user-names", Some(PASSWD), Some(GROUP));
+        for user in ["alice", "1000"] {
+            let alice = resolve_user(&root, user, &[]).unwrap();
+            assert_eq!((alice.uid, alice.gid), (1000, 1000), "{}", user);
+            assert_eq!(alice.home, "/home/alice");
+            assert_eq!(alice.groups, vec![10, 100]);
+        }
+        let bob = resolve_user(&root, "bob", &[]).unwrap();
+        assert_eq!((bob.uid, bob.gid), (1001, 100));
+        assert_eq!(bob.groups, vec![10, 29]);
+        assert!(resolve_user(&root, "nobody", &[]).is_err());
+        // An entry with an invalid UID is skipped
+        assert!(resolve_user(&root, "broken", &[]).is_err());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn groups_resolve_by_name_or_id() {
+        let root = rootfs("user-groups", Some(PASSWD), Some(GROUP));
+        let alice = resolve_user(&root, "alice:wheel", &[]).unwrap();
+        assert_eq!((alice.uid, alice.gid), (1000, 10));
+        // The primary group isn't repeated as a supplementary group
+        assert_eq!(alice.groups, vec![100]);
+        let alice = resolve_user(&root, "1000:5", &[]).unwrap();
+        assert_eq!((alice.uid, alice.gid), (1000, 5));
+        assert_eq!(alice.groups, vec![10, 100]);
+        assert!(resolve_user(&root, "alice:nogroup", &[]).is_err());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn added_groups_are_merged() {
+        let root = rootfs("user-group-add", Some(PASSWD), Some(GROUP));
+        let alice = resolve_user(&root, "alice", &groups(&["audio", "wheel", "4242"])).unwrap();
+        assert_eq!(alice.groups, vec![10, 29, 100, 4242]);
+        let alice = resolve_user(&root, "alice", &groups(&["alice"])).unwrap();
+        assert_eq!(alice.groups, vec![10, 100]);
+        assert!(resolve_user(&root, "alice", &groups(&["nogroup"])).is_err());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn ids_without_passwd() {
+        let root = rootfs("user-no-passwd", None, None);
+        let user = resolve_user(&root, "1234", &[]).unwrap();
+        assert_eq!((user.uid, user.gid), (1234, 1234));
+        assert!(user.groups.is_empty());
+        assert_eq!(user.home, DEFAULT_HOME);
+        let user = resolve_user(&root, "1234:99", &groups(&["7"])).unwrap();
+        assert_eq!((user.uid, user.gid, user.groups), (1234, 99, vec![7]));
+        // Names can't be resolved
+        assert!(resolve_user(&root, "alice", &[]).is_err());
+        assert!(resolve_user(&root, "1234:users", &[]).is_err());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unknown_id_keeps_the_default_home() {
+        let root = rootfs("user-unknown-id", Some(PASSWD), Some(GROUP));
+        let user = resolve_user(&root, "4000", &[]).unwrap();
+        assert_eq!((user.uid, user.gid), (4000, 4000));
+        assert_eq!(user.home, DEFAULT_HOME);
+        remove_dir_all(root).unwrap();
+    }
+}